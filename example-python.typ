@@ -15,6 +15,6 @@
 #py("2 + 2 * 2 + 2 * 2")
 
 // If you uncomment the following line, you can enter a python expression
-// in the terminal where you run the filesystem thingy.
+// in the terminal where you run the filesystem thingy: commands read its stdin.
 // The compiler will wait, the filesystem should not.
 // #py("eval(input())")
@@ -90,17 +90,49 @@
   assert.eq("!", do-with-shell-escape("reset", discriminator))
 }
 
+#let sleep(milliseconds, discriminator: "") = {
+  let disc-hash = hash(discriminator + "gIbBeRiSh" + str(milliseconds))
+  assert.eq("!", do-with-shell-escape("sleep_" + str(milliseconds), disc-hash))
+}
+
+#let random-hex(discriminator: "") = {
+  do-with-shell-escape("random", hash(discriminator + "gIbBeRiSh"))
+}
+
+#let nonce(discriminator: "") = {
+  int(do-with-shell-escape("nonce", hash(discriminator + "gIbBeRiSh")))
+}
+
 #let exec-command-async(
   command,
   discriminator: "",
+  key: none,
 ) = {
   let disc-hash = hash(discriminator + "gIbBeRiSh" + command)
-  reset-and-terminate-all(discriminator: disc-hash)
+  // Keyed commands leave the others running, they only supersede their own key.
+  if key == none {
+    reset-and-terminate-all(discriminator: disc-hash)
+  }
   for part in chunks(command, 32) {
     let part-hash = hash(encode-hex(part) + disc-hash)
     assert.eq("!", do-with-shell-escape(encode-hex(part), part-hash))
   }
-  assert.eq("!", do-with-shell-escape("exec", disc-hash))
+  let action = if key == none { "exec" } else { "exec_" + hash(key) }
+  assert.eq("!", do-with-shell-escape(action, disc-hash))
+}
+
+#let prefetch-commands(
+  commands,
+  discriminator: "",
+) = {
+  let disc-hash = hash(discriminator + "gIbBeRiSh" + commands.join("\n"))
+  reset-and-terminate-all(discriminator: disc-hash)
+  let manifest = commands.map(encode-hex).join("00")
+  for part in chunks(manifest, 64) {
+    let part-hash = hash(part + disc-hash)
+    assert.eq("!", do-with-shell-escape(part, part-hash))
+  }
+  assert.eq("!", do-with-shell-escape("prefetch", disc-hash))
 }
 
 #let wait-one(
@@ -122,6 +154,20 @@
   do-with-shell-escape("stderr" + format, disc-hash, fn: method)
 }
 
+#let get-output(discriminator: "", structured: false) = {
+  let disc-hash = hash(discriminator + "gIbBeRiSh")
+  if structured {
+    do-with-shell-escape("output.json", disc-hash, fn: json)
+  } else {
+    do-with-shell-escape("output", disc-hash)
+  }
+}
+
+#let get-result(discriminator: "") = {
+  let disc-hash = hash(discriminator + "gIbBeRiSh")
+  do-with-shell-escape("result.cbor", disc-hash, fn: cbor)
+}
+
 #let exec-command(
   command,
   method-stdout: read, 
@@ -130,9 +176,10 @@
   format-stderr: "",
   custom-hash: "",
   allow-non-zero-error-code: true,
+  key: none,
 ) = {
   let command-hash = hash(command + "GiBbErIsH" + custom-hash)
-  exec-command-async(command, discriminator: command-hash)
+  exec-command-async(command, discriminator: command-hash, key: key)
   let data = wait-one(discriminator: command-hash)
 
   if data.command.trim() != command.trim() {
@@ -153,6 +200,11 @@
   (stdout: stdout, stderr: stderr, error-code: data.result.error_code)
 }
 
+#let run-command(command, method: read, format: "", discriminator: "") = {
+  let disc-hash = hash(discriminator + "gIbBeRiSh" + command)
+  method(shell-escape-root + "run/" + disc-hash + "_" + encode-hex(command) + format)
+}
+
 #let http-get(url, method: read, format: "") = {
   let command = "curl -sS \"" + url + "\""
   let result = exec-command(command, method-stdout: method, format-stdout: format)
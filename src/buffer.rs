@@ -0,0 +1,298 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Size of the pieces spooled data is read in when it has to be processed as a whole.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Where and when large buffers are moved out of memory.
+#[derive(Clone, Debug)]
+pub struct Spool {
+    pub directory: PathBuf,
+    /// Buffers larger than this many bytes are moved to disk.
+    pub threshold: u64,
+}
+
+impl Spool {
+    /// Creates an anonymous file in the spool directory. The file is unlinked right away,
+    /// so it is cleaned up by the kernel once the last handle is closed,
+    /// even if the daemon is killed.
+    fn create_file(&self) -> io::Result<File> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        std::fs::create_dir_all(&self.directory)?;
+
+        let path = self.directory.join(format!(
+            "{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed),
+        ));
+
+        let file = File::options().read(true).write(true).create_new(true).open(&path)?;
+        std::fs::remove_file(&path)?;
+        Ok(file)
+    }
+}
+
+/// Something bytes can be read from by offset.
+pub trait ReadRange {
+    fn len(&self) -> usize;
+
+    /// Reads the given range, clipped to the length of the data.
+    /// Only spooled data can fail to be read.
+    fn read_range(&self, range: Range<usize>) -> io::Result<Cow<'_, [u8]>>;
+
+    /// Calls the function on consecutive pieces of the whole data, stopping at the first error.
+    fn for_each_chunk(&self, mut f: impl FnMut(&[u8]) -> io::Result<()>) -> io::Result<()> where Self: Sized {
+        for start in (0..self.len()).step_by(READ_CHUNK_SIZE) {
+            f(&self.read_range(start..start + READ_CHUNK_SIZE)?)?;
+        }
+        Ok(())
+    }
+
+    /// Everything at once, which is only fine for small data.
+    #[cfg(test)]
+    fn to_vec(&self) -> io::Result<Vec<u8>> where Self: Sized {
+        Ok(self.read_range(0..self.len())?.into_owned())
+    }
+}
+
+#[derive(Debug)]
+pub struct SpooledFile {
+    file: File,
+    len: usize,
+}
+
+impl SpooledFile {
+    fn read_range(&self, range: Range<usize>) -> io::Result<Vec<u8>> {
+        let range = range.start.min(self.len)..range.end.min(self.len);
+        let mut data = vec![0; range.len()];
+        self.file.read_exact_at(&mut data, range.start as u64)?;
+        Ok(data)
+    }
+}
+
+/// Immutable bytes, cheap to clone.
+#[derive(Clone, Debug)]
+pub enum Blob {
+    Memory(Arc<[u8]>),
+    Spooled(Arc<SpooledFile>),
+}
+
+impl Default for Blob {
+    fn default() -> Self {
+        Blob::Memory(Arc::default())
+    }
+}
+
+impl From<Vec<u8>> for Blob {
+    fn from(data: Vec<u8>) -> Self {
+        Blob::Memory(data.into())
+    }
+}
+
+impl From<&[u8]> for Blob {
+    fn from(data: &[u8]) -> Self {
+        Blob::Memory(data.into())
+    }
+}
+
+impl ReadRange for Blob {
+    fn len(&self) -> usize {
+        match self {
+            Blob::Memory(data) => data.len(),
+            Blob::Spooled(file) => file.len,
+        }
+    }
+
+    fn read_range(&self, range: Range<usize>) -> io::Result<Cow<'_, [u8]>> {
+        match self {
+            Blob::Memory(data) => Ok(Cow::Borrowed(&data[range.start.min(data.len())..range.end.min(data.len())])),
+            Blob::Spooled(file) => file.read_range(range).map(Cow::Owned),
+        }
+    }
+}
+
+/// A growing buffer, which moves to disk once it gets larger than the spool threshold,
+/// and back to memory if the disk fails it.
+#[derive(Debug, Default)]
+pub struct OutputBuffer {
+    memory: Vec<u8>,
+    spooled: Option<SpooledFile>,
+    spool: Option<Spool>,
+}
+
+impl OutputBuffer {
+    pub fn new(spool: Option<Spool>) -> Self {
+        Self { memory: Vec::new(), spooled: None, spool }
+    }
+
+    pub fn append(&mut self, data: &[u8]) {
+        if let Some(spooled) = &mut self.spooled {
+            match spooled.file.write_all_at(data, spooled.len as u64) {
+                Ok(()) => {
+                    spooled.len += data.len();
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("Failed to write spooled output, moving it back to memory: {}", e);
+                    self.unspool();
+                }
+            }
+        }
+
+        self.memory.extend_from_slice(data);
+
+        let Some(spool) = self.spool.as_ref().filter(|spool| self.memory.len() as u64 > spool.threshold) else {
+            return;
+        };
+
+        match spool.create_file().and_then(|mut file| file.write_all(&self.memory).map(|_| file)) {
+            Ok(file) => {
+                self.spooled = Some(SpooledFile { file, len: self.memory.len() });
+                self.memory = Vec::new();
+            }
+            Err(e) => {
+                eprintln!("Failed to spool output, keeping it in memory: {}", e);
+                self.spool = None;
+            }
+        }
+    }
+
+    /// Moves spooled data back to memory, for good.
+    fn unspool(&mut self) {
+        let Some(spooled) = self.spooled.take() else {
+            return;
+        };
+
+        self.spool = None;
+        self.memory = read_back(&spooled);
+    }
+
+    /// An immutable snapshot of the buffer. Spooled data is shared rather than copied,
+    /// unless the spool file can't be duplicated, for example when the daemon is out of
+    /// file descriptors.
+    pub fn freeze(&self) -> Blob {
+        let Some(spooled) = &self.spooled else {
+            return self.memory.as_slice().into();
+        };
+
+        match spooled.file.try_clone() {
+            Ok(file) => Blob::Spooled(Arc::new(SpooledFile { file, len: spooled.len })),
+            Err(e) => {
+                eprintln!("Failed to duplicate spool file, copying it to memory: {}", e);
+                read_back(spooled).into()
+            }
+        }
+    }
+
+    /// The content of the buffer, once it is complete.
+    pub fn into_blob(self) -> Blob {
+        match self.spooled {
+            Some(spooled) => Blob::Spooled(Arc::new(spooled)),
+            None => self.memory.into(),
+        }
+    }
+}
+
+/// All of the spooled data. Data which can't be read back is replaced with zeroes,
+/// so that everything after it stays where it was.
+fn read_back(spooled: &SpooledFile) -> Vec<u8> {
+    spooled.read_range(0..spooled.len).unwrap_or_else(|e| {
+        eprintln!("Failed to read spooled output back, {} bytes of it are lost: {}", spooled.len, e);
+        vec![0; spooled.len]
+    })
+}
+
+/// Lets results be written straight into the spool as they are generated.
+impl Write for OutputBuffer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.append(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ReadRange for OutputBuffer {
+    fn len(&self) -> usize {
+        match &self.spooled {
+            Some(spooled) => spooled.len,
+            None => self.memory.len(),
+        }
+    }
+
+    fn read_range(&self, range: Range<usize>) -> io::Result<Cow<'_, [u8]>> {
+        match &self.spooled {
+            Some(spooled) => spooled.read_range(range).map(Cow::Owned),
+            None => Ok(Cow::Borrowed(&self.memory[range.start.min(self.memory.len())..range.end.min(self.memory.len())])),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::AsRawFd;
+    use super::*;
+
+    #[test]
+    fn test_spooling() {
+        // A directory of its own, since it is checked for leftovers.
+        let spool = Spool {
+            directory: std::env::temp_dir().join(format!("typst-shell-escape-test-spooling-{}", std::process::id())),
+            threshold: 4,
+        };
+
+        let mut buffer = OutputBuffer::new(Some(spool.clone()));
+        buffer.append(b"abc");
+        assert!(buffer.spooled.is_none());
+        buffer.append(b"def");
+        assert!(buffer.spooled.is_some());
+        buffer.append(b"gh");
+
+        assert_eq!(buffer.len(), 8);
+        assert_eq!(buffer.read_range(2..5).unwrap(), &b"cde"[..]);
+        assert_eq!(buffer.read_range(6..100).unwrap(), &b"gh"[..]);
+
+        let blob = buffer.freeze();
+        assert!(matches!(blob, Blob::Spooled(..)));
+        assert_eq!(blob.to_vec().unwrap(), b"abcdefgh");
+
+        // Spool files are never visible in the directory.
+        assert_eq!(std::fs::read_dir(&spool.directory).unwrap().count(), 0);
+
+        let mut buffer = OutputBuffer::new(Some(spool.clone()));
+        buffer.append(b"abc");
+        assert!(matches!(buffer.into_blob(), Blob::Memory(..)));
+        std::fs::remove_dir(&spool.directory).unwrap();
+    }
+
+    #[test]
+    fn test_spool_failure() {
+        let spool = Spool {
+            directory: std::env::temp_dir().join(format!("typst-shell-escape-test-spool-failure-{}", std::process::id())),
+            threshold: 4,
+        };
+
+        let mut buffer = OutputBuffer::new(Some(spool.clone()));
+        buffer.append(b"abcdef");
+
+        // Reopening the spool file read-only makes further writes fail.
+        let spooled = buffer.spooled.as_mut().unwrap();
+        spooled.file = File::open(format!("/proc/self/fd/{}", spooled.file.as_raw_fd())).unwrap();
+
+        buffer.append(b"gh");
+        assert!(buffer.spooled.is_none());
+        buffer.append(b"ijklmn");
+        assert!(buffer.spooled.is_none());
+        assert_eq!(buffer.into_blob().to_vec().unwrap(), b"abcdefghijklmn");
+        std::fs::remove_dir(&spool.directory).unwrap();
+    }
+}
@@ -0,0 +1,239 @@
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use crate::buffer::Spool;
+use crate::policy::Policy;
+use crate::trust::TrustStore;
+
+/// Daemon configuration. Loaded from a JSON file given with `--config <path>`,
+/// every field is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub output: OutputConfig,
+    pub spool: SpoolConfig,
+    pub sessions: SessionsConfig,
+    pub supersession: SupersessionConfig,
+    pub policy: Policy,
+    pub approval: ApprovalConfig,
+    pub sandbox: SandboxConfig,
+    pub landlock: LandlockConfig,
+    pub user: UserConfig,
+}
+
+/// What to do with a command whose output stream exceeds the size limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowAction {
+    /// Keep the command running, but stop collecting its output.
+    #[default]
+    Truncate,
+    /// Kill the command as soon as the limit is exceeded.
+    Kill,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    /// Maximum number of bytes collected from the stdout of a command. `null` removes the limit.
+    pub max_stdout_size: Option<u64>,
+    /// Same for stderr.
+    pub max_stderr_size: Option<u64>,
+    pub on_overflow: OverflowAction,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            max_stdout_size: Some(64 * 1024 * 1024),
+            max_stderr_size: Some(64 * 1024 * 1024),
+            on_overflow: OverflowAction::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpoolConfig {
+    /// Directory where large outputs are kept instead of memory.
+    pub directory: PathBuf,
+    /// Outputs larger than this many bytes are moved to disk. `null` keeps everything in memory.
+    pub threshold: Option<u64>,
+}
+
+impl Default for SpoolConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("/tmp/typst-shell-escape/spool"),
+            threshold: Some(1024 * 1024),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionsConfig {
+    /// Give every process using the root of the filesystem a session of its own.
+    pub per_process: bool,
+    /// How many sessions can be started in `sessions/`, each of them has a shell.
+    pub max_named: usize,
+}
+
+impl Default for SessionsConfig {
+    fn default() -> Self {
+        Self { per_process: true, max_named: 32 }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SupersessionConfig {
+    /// Milliseconds a keyed command waits before it starts, so that a burst of
+    /// recompilations only runs the last one.
+    pub debounce: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApprovalConfig {
+    /// Ask in the terminal before running a command for the first time.
+    pub enabled: bool,
+    /// Where the decisions to always allow or deny a command are kept.
+    pub trust_store: PathBuf,
+}
+
+impl Default for ApprovalConfig {
+    fn default() -> Self {
+        let config_home = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .unwrap_or_else(|| PathBuf::from("/tmp/typst-shell-escape"));
+
+        Self {
+            enabled: false,
+            trust_store: config_home.join("typst-shell-escape").join("trust.json"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SandboxConfig {
+    /// Run every job in its own namespaces, see [crate::sandbox].
+    pub enabled: bool,
+    /// Leave the network of the sandbox alone instead of cutting it off.
+    pub network: bool,
+    /// Directories which are empty in the sandbox, except for the project if it is inside.
+    pub hide: Vec<PathBuf>,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            network: false,
+            hide: std::env::var_os("HOME").map(PathBuf::from).into_iter().collect(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LandlockConfig {
+    /// Restrict what jobs can access on the filesystem, see [crate::landlock].
+    pub enabled: bool,
+    /// Paths jobs can read and execute.
+    pub read: Vec<PathBuf>,
+    /// Paths jobs can do anything with.
+    pub write: Vec<PathBuf>,
+    /// Let jobs write to the project, not just read it.
+    pub write_project: bool,
+    /// Where the scratch directories of the jobs are created.
+    pub scratch: PathBuf,
+}
+
+impl Default for LandlockConfig {
+    fn default() -> Self {
+        let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect();
+        Self {
+            enabled: false,
+            read: paths(&["/bin", "/sbin", "/usr", "/lib", "/lib32", "/lib64", "/etc", "/opt", "/dev", "/proc", "/sys"]),
+            write: paths(&["/dev/null"]),
+            write_project: false,
+            scratch: PathBuf::from("/tmp/typst-shell-escape/scratch"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserConfig {
+    /// Run commands as this user instead of the one running the daemon.
+    pub uid: Option<u32>,
+    /// And as this group, required along with `uid`.
+    pub gid: Option<u32>,
+    /// Let commands run as root, which is refused otherwise.
+    pub allow_root: bool,
+}
+
+impl Config {
+    pub fn spool(&self) -> Option<Spool> {
+        Some(Spool {
+            directory: self.spool.directory.clone(),
+            threshold: self.spool.threshold?,
+        })
+    }
+
+    pub fn trust_store(&self) -> Option<TrustStore> {
+        self.approval.enabled.then(|| TrustStore { path: self.approval.trust_store.clone() })
+    }
+
+    pub fn load(path: &Path) -> Self {
+        let file = std::fs::File::open(path).expect("Failed to open config file");
+        serde_json::from_reader(file).expect("Failed to parse config file")
+    }
+
+    /// Loads the config file given in the command line arguments, if any.
+    pub fn from_args() -> Self {
+        let mut args = std::env::args().skip(1);
+        let mut path = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => path = Some(PathBuf::from(args.next().expect("Expected a path after --config"))),
+                _ => panic!("Unknown argument: {}", arg),
+            }
+        }
+
+        path.map(|path| Self::load(&path)).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config: Config = serde_json::from_str("{}").unwrap();
+        assert_eq!(config.output.max_stdout_size, Some(64 * 1024 * 1024));
+        assert_eq!(config.output.max_stderr_size, Some(64 * 1024 * 1024));
+        assert_eq!(config.output.on_overflow, OverflowAction::Truncate);
+
+        let config: Config = serde_json::from_str(r#"{"output": {"max_stdout_size": null, "max_stderr_size": 10, "on_overflow": "kill"}}"#).unwrap();
+        assert_eq!(config.output.max_stdout_size, None);
+        assert_eq!(config.output.max_stderr_size, Some(10));
+        assert_eq!(config.output.on_overflow, OverflowAction::Kill);
+
+        assert!(serde_json::from_str::<Config>(r#"{"outptu": {}}"#).is_err());
+
+        let config: Config = serde_json::from_str(r#"{"spool": {"threshold": null}}"#).unwrap();
+        assert!(config.spool().is_none());
+        assert!(config.sessions.per_process);
+        assert_eq!(config.sessions.max_named, 32);
+        assert_eq!(config.supersession.debounce, 0);
+        assert!(config.trust_store().is_none());
+
+        let config: Config = serde_json::from_str(r#"{"approval": {"enabled": true, "trust_store": "/trust.json"}}"#).unwrap();
+        assert_eq!(config.trust_store().unwrap().path, Path::new("/trust.json"));
+    }
+}
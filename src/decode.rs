@@ -14,6 +14,13 @@ pub fn hex_decode(hex: Vec<u8>) -> Vec<u8> {
     }).collect()
 }
 
+pub fn hex_encode(bytes: &[u8]) -> Vec<u8> {
+    const HEX_DIGITS: &[u8] = b"0123456789abcdef";
+    bytes.iter()
+        .flat_map(|&byte| [HEX_DIGITS[(byte >> 4) as usize], HEX_DIGITS[(byte & 0xf) as usize]])
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -24,4 +31,11 @@ mod tests {
        assert_eq!(hex_decode(b"00a742".to_vec()), b"\x00\xa7\x42");
        assert_eq!(hex_decode(b"".to_vec()), b"");
    }
+
+   #[test]
+    fn test_encode() {
+       assert_eq!(hex_encode(b"\x00\xa7\x42"), b"00a742");
+       assert_eq!(hex_encode(b""), b"");
+       assert_eq!(hex_decode(hex_encode(b"ls -la /")), b"ls -la /");
+   }
 }
@@ -1,26 +1,442 @@
-use std::time::{Duration, SystemTime};
-use std::collections::HashMap;
-use fuser::{FileAttr, Filesystem, FileType, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request};
+use std::time::{Duration, Instant, SystemTime};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use fuser::{FileAttr, Filesystem, FileType, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, Request};
+use fuser::consts::FOPEN_DIRECT_IO;
 use std::ffi::OsStr;
-use std::ops::Range;
+use std::io::{self, Read};
+use std::ops::{Deref, Index, IndexMut, Range};
 use std::os::unix::ffi::OsStrExt;
-use std::sync::mpsc;
-use crate::decode::hex_decode;
-use crate::shell::{Command, FinishedCommand, ExecutionResult};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use crate::buffer::{Blob, OutputBuffer, ReadRange};
+use crate::config::Config;
+use crate::decode::{hex_decode, hex_encode};
+use crate::jobs::{JobTable, LiveJob, Rendering, Stream};
+use crate::names::{self, Action, NameError};
+use crate::notify::Notifier;
+use crate::process::{self, ProcessWatch};
+use crate::shell::{self, Command, FinishedCommand, ExecutionResult, Job};
+use crate::timer::Timer;
+use crate::trust::Decision;
 
 const TTL: Duration = Duration::from_secs(1);
 
+/// Cache time of files which stay in place. The kernel is notified whenever they change.
+const STABLE_TTL: Duration = Duration::from_secs(60);
+
+const ROOT_DIR_INODE: u64 = 1;
+const RUN_DIR_INODE: u64 = 2;
+const JOBS_DIR_INODE: u64 = 3;
+const SESSIONS_DIR_INODE: u64 = 4;
+
 const FILE_INODE_OFFSET: u64 = 256;
 
 const SUCCESS_MESSAGE: &[u8] = b"!";
 
+/// How much of the log of a session is kept, older lines are dropped.
+const LOG_LIMIT: usize = 1024 * 1024;
+
+/// Number of random bytes served by the random file, before hex encoding.
+const RANDOM_BYTES: usize = 16;
+
+/// Key of a session in [ShellEscapeFs::sessions].
+type SessionId = usize;
+
+/// The session served from the root of the filesystem.
+const DEFAULT_SESSION: SessionId = 0;
+
 #[derive(Clone, Debug)]
+#[allow(clippy::enum_variant_names)]
 enum FsEntry {
-    ExecFile(),
-    WaitFile(),
-    ResetFile(),
-    AppendDataFile(Vec<u8>),
-    ResultFile(Vec<u8>),
+    ExecFile(SessionId),
+    WaitFile(SessionId),
+    ResetFile(SessionId),
+    PrefetchFile(SessionId),
+    SleepFile(Duration),
+    AppendDataFile(SessionId, Vec<u8>),
+    KeyedExecFile(SessionId, Vec<u8>),
+    /// Immutable content, shared between all the reads of the file.
+    ResultFile(Blob),
+    /// Same, but made for a single lookup, like a random value. Never cached,
+    /// otherwise the next lookup would see the same file again.
+    FreshFile(Blob),
+    /// The only file which grows in place.
+    LogFile(Arc<Mutex<Log>>),
+    JobDir(SessionId, Arc<LiveJob>),
+    /// Output of a job, which may still be growing.
+    LiveOutputFile(Arc<LiveJob>, LiveView),
+    /// Stdout of a command run on lookup, only handed out once it has finished successfully.
+    RunOutput(Arc<Run>),
+}
+
+/// Which part of the output of a job a live file shows.
+#[derive(Clone, Copy, Debug)]
+enum LiveView {
+    Stream(Stream),
+    /// Both streams interleaved.
+    Combined(Rendering),
+}
+
+impl LiveView {
+    fn len(self, job: &LiveJob) -> usize {
+        match self {
+            LiveView::Stream(stream) => job.output(stream).lock().unwrap().len(),
+            LiveView::Combined(rendering) => job.combined_len(rendering),
+        }
+    }
+}
+
+/// The most recent lines of a log, up to [LOG_LIMIT] bytes.
+#[derive(Debug, Default)]
+struct Log {
+    data: VecDeque<u8>,
+}
+
+impl Log {
+    fn append(&mut self, data: &[u8]) {
+        self.data.extend(data);
+        if self.data.len() <= LOG_LIMIT {
+            return;
+        }
+
+        // Whole lines are dropped, so that the log still starts with one.
+        let excess = self.data.len() - LOG_LIMIT;
+        let end = self.data.range(excess..)
+            .position(|&c| c == b'\n')
+            .map_or(self.data.len(), |newline| excess + newline + 1);
+        self.data.drain(..end);
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn contents(&mut self) -> &[u8] {
+        self.data.make_contiguous()
+    }
+}
+
+/// A command run on lookup of its name in `run/`. Lookups made while it runs are answered
+/// from the thread running it, once it finishes.
+struct Run {
+    inode: u64,
+    state: Mutex<RunState>,
+    /// Kernel references to files, see [ShellEscapeFs::lookups], since answering adds one.
+    lookups: Arc<Mutex<HashMap<u64, u64>>>,
+    /// Log of the session, with the command buffer as it was when the command started.
+    log: Arc<Mutex<Log>>,
+    buffer: Vec<u8>,
+}
+
+enum RunState {
+    Running(Vec<ReplyEntry>),
+    /// The stdout of the command, or the errno lookups fail with.
+    Finished(Result<Blob, i32>),
+}
+
+impl std::fmt::Debug for Run {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Run").field("inode", &self.inode).finish_non_exhaustive()
+    }
+}
+
+impl Run {
+    /// Answers a lookup right away if the command has finished, or once it does.
+    fn reply(&self, reply: ReplyEntry) {
+        match &mut *self.state.lock().unwrap() {
+            RunState::Running(waiting) => waiting.push(reply),
+            RunState::Finished(result) => self.answer(result, reply),
+        }
+    }
+
+    /// Keeps the result of the command and answers every lookup waiting for it.
+    fn finish(&self, result: FinishedCommand) {
+        let FinishedCommand::Execution(result) = result else {
+            unreachable!("Running a command always produces an execution");
+        };
+
+        let result = match result.result {
+            ExecutionResult::Ran { error_code: 0, stdout, .. } => Ok(stdout.data),
+            ExecutionResult::Ran { error_code, .. } => {
+                self.log(&format!("Run failed with error code {}", error_code));
+                Err(libc::EIO)
+            }
+            ExecutionResult::FailedToSpawn(e) | ExecutionResult::FailedToWait(e) => {
+                self.log(&format!("Run failed: {}", e));
+                Err(libc::EIO)
+            }
+            ExecutionResult::Cancelled(reason) => {
+                self.log(&format!("Run cancelled: {}", reason));
+                Err(libc::EIO)
+            }
+            ExecutionResult::Denied(..) => unreachable!("Runs are checked against the policy before they start"),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let RunState::Running(waiting) = std::mem::replace(&mut *state, RunState::Finished(result)) else {
+            unreachable!("Commands finish once");
+        };
+        let RunState::Finished(result) = &*state else {
+            unreachable!();
+        };
+        for reply in waiting {
+            self.answer(result, reply);
+        }
+    }
+
+    fn answer(&self, result: &Result<Blob, i32>, reply: ReplyEntry) {
+        match result {
+            Ok(stdout) => {
+                // The same as the attributes of any other result file.
+                let attrs = RealizedFsEntry { inode: self.inode, entry: FsEntry::ResultFile(stdout.clone()) }.get_attrs();
+                *self.lookups.lock().unwrap().entry(self.inode).or_default() += 1;
+                reply.entry(&STABLE_TTL, &attrs, 0);
+            }
+            Err(errno) => reply.error(*errno),
+        }
+    }
+
+    fn log(&self, message: &str) {
+        self.log.lock().unwrap().append(log_line(&self.buffer, message).as_bytes());
+    }
+
+    /// The stdout of the command, if it has finished successfully.
+    fn output(&self) -> Option<Blob> {
+        match &*self.state.lock().unwrap() {
+            RunState::Finished(Ok(stdout)) => Some(stdout.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Inodes of everything inside `jobs/<id>/`.
+#[derive(Clone, Copy, Debug)]
+struct JobInodes {
+    dir: u64,
+    command: u64,
+    stdout: u64,
+    stderr: u64,
+    output: u64,
+    output_json: u64,
+}
+
+impl JobInodes {
+    fn all(&self) -> [u64; 6] {
+        [self.dir, self.command, self.stdout, self.stderr, self.output, self.output_json]
+    }
+}
+
+/// Inodes of the files with the result of the last command waited for.
+#[derive(Clone, Copy, Debug)]
+struct ResultInodes {
+    diagnostics: u64,
+    stdout: u64,
+    stderr: u64,
+    result_json: u64,
+    result_cbor: u64,
+    output: u64,
+    output_json: u64,
+}
+
+impl ResultInodes {
+    fn all(&self) -> [u64; 7] {
+        [
+            self.diagnostics,
+            self.stdout,
+            self.stderr,
+            self.result_json,
+            self.result_cbor,
+            self.output,
+            self.output_json,
+        ]
+    }
+}
+
+/// Which directory of a session an inode is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SessionDir {
+    Root,
+    Run,
+    Jobs,
+}
+
+impl FsEntry {
+    /// The session the file acts on, if it is bound to one.
+    fn session(&self) -> Option<SessionId> {
+        match self {
+            FsEntry::ExecFile(session)
+            | FsEntry::WaitFile(session)
+            | FsEntry::ResetFile(session)
+            | FsEntry::PrefetchFile(session)
+            | FsEntry::AppendDataFile(session, _)
+            | FsEntry::KeyedExecFile(session, _)
+            | FsEntry::JobDir(session, _) => Some(*session),
+            _ => None,
+        }
+    }
+}
+
+/// Everything a compiler talks to: a command buffer, special files, and a shell with its jobs.
+/// Every session is independent of the others, so that several compilers can share the daemon
+/// without mixing up their commands or terminating each other's jobs.
+struct Session {
+    kind: SessionKind,
+
+    /// When the session was last looked up by name, for reclaiming named sessions.
+    last_used: Instant,
+
+    /// The command buffer, already decoded from hex.
+    decoded_command_buffer: Vec<u8>,
+
+    dir_inode: u64,
+    run_dir_inode: u64,
+    jobs_dir_inode: u64,
+
+    // Inodes of the special files.
+    // Those stay the same, every open of them is served with direct I/O
+    // from its own file handle, so kernel caching doesn't get in the way.
+    exec_file_inode: u64,
+    wait_file_inode: u64,
+    reset_file_inode: u64,
+    prefetch_file_inode: u64,
+    results: ResultInodes,
+    log_file_inode: u64,
+
+    /// Inodes of the files of every job which was ever listed or looked up.
+    job_inodes: HashMap<u64, JobInodes>,
+
+    /// Files which have to stay even if the kernel doesn't know about them: the special files,
+    /// files of jobs which are still in the job table, and outputs of runs.
+    pinned: HashSet<u64>,
+
+    /// Commands run through `run/` since the last reset, by the name they were looked up with.
+    runs: HashMap<Vec<u8>, Arc<Run>>,
+
+    /// Jobs of the shell, for watching their output.
+    jobs: Arc<JobTable>,
+
+    /// A channel provided for sending commands to the shell.
+    command_channel: mpsc::Sender<Command>,
+
+    /// A channel provided for receiving results from the shell.
+    results_channel: mpsc::Receiver<FinishedCommand>,
+}
+
+/// What a session was started for.
+enum SessionKind {
+    /// The session served from the root of the filesystem.
+    Default,
+    /// A session in `sessions/`, by its name.
+    Named(Vec<u8>),
+    /// The session of a process using the root, see [ShellEscapeFs::caller_session].
+    /// It can't be reached through `sessions/`, and is dropped once the process exits.
+    Process(u32, ProcessWatch),
+}
+
+/// All the sessions by their ids. Ids are never reused, so that
+/// files left over from a dropped session can't reach another one.
+#[derive(Default)]
+struct Sessions {
+    sessions: BTreeMap<SessionId, Session>,
+    next_id: SessionId,
+}
+
+impl Sessions {
+    fn next_id(&self) -> SessionId {
+        self.next_id
+    }
+
+    fn insert(&mut self, session: Session) -> SessionId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.sessions.insert(id, session);
+        id
+    }
+
+    fn remove(&mut self, id: SessionId) -> Option<Session> {
+        self.sessions.remove(&id)
+    }
+
+    fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Sessions in the order they were started, the default one first.
+    fn iter(&self) -> impl Iterator<Item = (SessionId, &Session)> {
+        self.sessions.iter().map(|(&id, session)| (id, session))
+    }
+
+    fn values(&self) -> impl Iterator<Item = &Session> {
+        self.sessions.values()
+    }
+
+    fn values_mut(&mut self) -> impl Iterator<Item = &mut Session> {
+        self.sessions.values_mut()
+    }
+
+    fn find(&self, f: impl Fn(&Session) -> bool) -> Option<SessionId> {
+        self.iter().find(|(_, session)| f(session)).map(|(id, _)| id)
+    }
+}
+
+impl Index<SessionId> for Sessions {
+    type Output = Session;
+
+    fn index(&self, id: SessionId) -> &Session {
+        self.sessions.get(&id).expect("Session is gone, should be impossible")
+    }
+}
+
+impl IndexMut<SessionId> for Sessions {
+    fn index_mut(&mut self, id: SessionId) -> &mut Session {
+        self.sessions.get_mut(&id).expect("Session is gone, should be impossible")
+    }
+}
+
+impl Session {
+    /// Entries of the directory of the session, as reported by `readdir`.
+    fn dir_entries(&self) -> Vec<(u64, FileType, &'static str)> {
+        let mut entries = vec![
+            (self.dir_inode, FileType::Directory, "."),
+            (self.dir_inode, FileType::Directory, ".."),
+            (self.run_dir_inode, FileType::Directory, "run"),
+            (self.jobs_dir_inode, FileType::Directory, "jobs"),
+            (self.exec_file_inode, FileType::RegularFile, "exec"),
+            (self.wait_file_inode, FileType::RegularFile, "wait"),
+            (self.reset_file_inode, FileType::RegularFile, "reset"),
+            (self.prefetch_file_inode, FileType::RegularFile, "prefetch"),
+            (self.results.diagnostics, FileType::RegularFile, "diagnostics"),
+            (self.results.stdout, FileType::RegularFile, "stdout"),
+            (self.results.stderr, FileType::RegularFile, "stderr"),
+            (self.results.result_json, FileType::RegularFile, "result.json"),
+            (self.results.result_cbor, FileType::RegularFile, "result.cbor"),
+            (self.results.output, FileType::RegularFile, "output"),
+            (self.results.output_json, FileType::RegularFile, "output.json"),
+            (self.log_file_inode, FileType::RegularFile, "log"),
+        ];
+
+        if self.dir_inode == ROOT_DIR_INODE {
+            entries.push((SESSIONS_DIR_INODE, FileType::Directory, "sessions"));
+        } else {
+            entries[1].0 = SESSIONS_DIR_INODE;
+        }
+
+        entries
+    }
+
+    /// Whether the file belongs to the session and has to stay even if the kernel doesn't know about it:
+    /// the special files, and files of jobs which are still in the job table.
+    fn is_pinned(&self, inode: u64) -> bool {
+        self.pinned.contains(&inode)
+    }
+}
+
+/// A file opened by someone. Every open sees the file as it was when it was opened,
+/// except for the files which are meant to grow.
+struct OpenFile {
+    entry: FsEntry,
+    /// Whether the file was read through this handle yet. Actions happen on the first read.
+    read: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -33,10 +449,19 @@ impl RealizedFsEntry {
     /// Filesystem attributes of a file. Size is the most important one.
     fn get_attrs(&self) -> FileAttr {
         let size = match &self.entry {
-            FsEntry::ExecFile() | FsEntry::WaitFile() | FsEntry::ResetFile() =>
+            FsEntry::ExecFile(..) | FsEntry::WaitFile(..) | FsEntry::ResetFile(..) | FsEntry::PrefetchFile(..) =>
                 SUCCESS_MESSAGE.len(),
-            FsEntry::AppendDataFile(..) => SUCCESS_MESSAGE.len(),
-            FsEntry::ResultFile(data) => data.len(),
+            FsEntry::AppendDataFile(..) | FsEntry::KeyedExecFile(..) | FsEntry::SleepFile(..) => SUCCESS_MESSAGE.len(),
+            FsEntry::ResultFile(data) | FsEntry::FreshFile(data) => data.len(),
+            FsEntry::LogFile(data) => data.lock().unwrap().len(),
+            FsEntry::JobDir(..) => 0,
+            FsEntry::LiveOutputFile(job, view) => view.len(job),
+            FsEntry::RunOutput(run) => run.output().map_or(0, |output| output.len()),
+        };
+
+        let (kind, perm) = match &self.entry {
+            FsEntry::JobDir(..) => (FileType::Directory, 0o555),
+            _ => (FileType::RegularFile, 0o444),
         };
 
         FileAttr {
@@ -47,8 +472,8 @@ impl RealizedFsEntry {
             mtime: SystemTime::now(),
             ctime: SystemTime::now(),
             crtime: SystemTime::now(),
-            kind: FileType::RegularFile,
-            perm: 0o444,
+            kind,
+            perm,
             nlink: 0,
             uid: 0,
             gid: 0,
@@ -58,24 +483,19 @@ impl RealizedFsEntry {
         }
     }
 
-    /// If the entry is a [FsEntry::ResultFile], write the given data to it.
-    fn write_result(&mut self, data: Vec<u8>) {
+    /// If the entry is a [FsEntry::ResultFile], replace its content with the given data.
+    fn write_result(&mut self, data: impl Into<Blob>) {
         match &mut self.entry {
-            FsEntry::ResultFile(result) => {
-                result.clear();
-                result.extend_from_slice(&data);
-            }
+            FsEntry::ResultFile(result) => *result = data.into(),
             _ => panic!("Can't write result to non-result file"),
         }
     }
 
-    /// If the entry is a [FsEntry::ResultFile], append the given data to it.
-    fn append_result(&mut self, data: Vec<u8>) {
+    /// If the entry is a [FsEntry::LogFile], append the given data to it.
+    fn append_log(&mut self, data: &[u8]) {
         match &mut self.entry {
-            FsEntry::ResultFile(result) => {
-                result.extend_from_slice(&data);
-            }
-            _ => panic!("Can't write result to non-result file"),
+            FsEntry::LogFile(log) => log.lock().unwrap().append(data),
+            _ => panic!("Can't append to non-log file"),
         }
     }
 }
@@ -83,125 +503,267 @@ impl RealizedFsEntry {
 // TODO: There is too many boilerplate here.
 // TODO: add special files for
 //     - [ ] list of commands being executed
-//     - [ ] lookahead for the command queue
-//     - [ ] sleep file, which sends content after some time from a different thread
-//     - [ ] random file, which sends random hex string every read
+//     - [x] lookahead for the command queue
+//     - [x] sleep file, which sends content after some time from a different thread
+//     - [x] random file, which sends random hex string every read
 
 pub struct ShellEscapeFs {
-    /// The command buffer, already decoded from hex.
-    decoded_command_buffer: Vec<u8>,
+    /// The default session first, then the ones created in `sessions/` and for processes.
+    /// Sessions of processes are dropped once they exit, named ones when there are too many.
+    sessions: Sessions,
 
-    // Inodes of the special files.
-    // Those files should be recreated from scratch after every use,
-    // because otherwise kernel caching will ruin our life.
-    exec_file_inode: u64,
-    wait_file_inode: u64,
-    reset_file_inode: u64,
+    /// The value served by the next lookup of the nonce file.
+    nonce: u64,
 
-    diagnostics_file_inode: u64,
-    stdout_file_inode: u64,
-    stderr_file_inode: u64,
-    log_file_inode: u64,
-
-    /// All the files in the filesystem. Technically causes a memory leak, but
-    /// It's going to be a small number of entries anyway
+    /// All the files in the filesystem. Files which are neither special
+    /// nor known to the kernel are removed, see [ShellEscapeFs::collect_garbage].
     inodes: HashMap<u64, RealizedFsEntry>,
 
-    /// A channel provided for sending commands to the shell.
-    command_channel: mpsc::Sender<Command>,
+    /// How many times the kernel has looked up each inode without forgetting it.
+    /// Shared with commands run on lookup, which answer the lookup themselves.
+    lookups: Arc<Mutex<HashMap<u64, u64>>>,
 
-    /// A channel provided for receiving results from the shell.
-    results_channel: mpsc::Receiver<FinishedCommand>,
+    open_files: HashMap<u64, OpenFile>,
+    next_file_handle: u64,
+
+    next_inode: u64,
+
+    config: Arc<Config>,
+
+    notifier: Arc<Notifier>,
+
+    /// Answers reads of sleep files once they are done sleeping.
+    timer: Timer,
 }
 
 impl ShellEscapeFs {
+    /// Creates the filesystem with the default session, served by the given shell.
     pub fn new(
+        config: Arc<Config>,
+        jobs: Arc<JobTable>,
         command_channel: mpsc::Sender<Command>,
         results_channel: mpsc::Receiver<FinishedCommand>,
     ) -> Self {
-        let mut inodes = HashMap::new();
-
-        let mut make_entry = |entry: FsEntry| {
-            let inode = inodes.len() as u64 + FILE_INODE_OFFSET;
-            let result = RealizedFsEntry { inode, entry };
-            inodes.insert(inode, result.clone());
-            inode
+        let mut fs = Self {
+            sessions: Sessions::default(),
+            next_inode: FILE_INODE_OFFSET,
+            inodes: HashMap::new(),
+            lookups: Arc::default(),
+            open_files: HashMap::new(),
+            next_file_handle: 1,
+            nonce: 0,
+            config,
+            notifier: Arc::default(),
+            timer: Timer::default(),
         };
 
-        let exec_file_inode = make_entry(FsEntry::ExecFile());
-        let wait_file_inode = make_entry(FsEntry::WaitFile());
-        let reset_file_inode = make_entry(FsEntry::ResetFile());
-        let diagnostics_file_inode = make_entry(FsEntry::ResultFile(Vec::new()));
-        let stdout_file_inode = make_entry(FsEntry::ResultFile(Vec::new()));
-        let stderr_file_inode = make_entry(FsEntry::ResultFile(Vec::new()));
-        let log_file_inode = make_entry(FsEntry::ResultFile(Vec::new()));
+        let dirs = (ROOT_DIR_INODE, RUN_DIR_INODE, JOBS_DIR_INODE);
+        fs.make_session(SessionKind::Default, dirs, jobs, command_channel, results_channel);
+        fs
+    }
+
+    /// Adds a session with the given directories, served by the given shell.
+    fn make_session(
+        &mut self,
+        kind: SessionKind,
+        (dir_inode, run_dir_inode, jobs_dir_inode): (u64, u64, u64),
+        jobs: Arc<JobTable>,
+        command_channel: mpsc::Sender<Command>,
+        results_channel: mpsc::Receiver<FinishedCommand>,
+    ) -> SessionId {
+        let id = self.sessions.next_id();
+        let mut make_result_file = || self.make_entry(FsEntry::ResultFile(Blob::default())).inode;
+
+        let results = ResultInodes {
+            diagnostics: make_result_file(),
+            stdout: make_result_file(),
+            stderr: make_result_file(),
+            result_json: make_result_file(),
+            result_cbor: make_result_file(),
+            output: make_result_file(),
+            output_json: make_result_file(),
+        };
 
-        Self {
+        let mut session = Session {
+            kind,
+            last_used: Instant::now(),
             decoded_command_buffer: Vec::new(),
-            inodes,
-            exec_file_inode,
-            wait_file_inode,
-            reset_file_inode,
-            diagnostics_file_inode,
-            stdout_file_inode,
-            stderr_file_inode,
-            log_file_inode,
+            dir_inode,
+            run_dir_inode,
+            jobs_dir_inode,
+            exec_file_inode: self.make_entry(FsEntry::ExecFile(id)).inode,
+            wait_file_inode: self.make_entry(FsEntry::WaitFile(id)).inode,
+            reset_file_inode: self.make_entry(FsEntry::ResetFile(id)).inode,
+            prefetch_file_inode: self.make_entry(FsEntry::PrefetchFile(id)).inode,
+            results,
+            log_file_inode: self.make_entry(FsEntry::LogFile(Arc::default())).inode,
+            job_inodes: HashMap::new(),
+            pinned: HashSet::new(),
+            runs: HashMap::new(),
+            jobs,
             command_channel,
             results_channel,
+        };
+
+        session.pinned = session.dir_entries().into_iter().map(|(inode, ..)| inode).collect();
+        self.sessions.insert(session)
+    }
+
+    /// Finds the session with the given name, or starts a new one with its own shell.
+    /// Fails if there are too many named sessions already and none of them can be dropped.
+    fn get_or_make_session(&mut self, name: &[u8]) -> Option<SessionId> {
+        let named = |session: &Session| matches!(&session.kind, SessionKind::Named(existing) if existing == name);
+        if let Some(id) = self.sessions.find(named) {
+            self.sessions[id].last_used = Instant::now();
+            return Some(id);
+        }
+
+        let named = self.sessions.iter()
+            .filter(|(_, session)| matches!(session.kind, SessionKind::Named(..)))
+            .collect::<Vec<_>>();
+        if named.len() >= self.config.sessions.max_named {
+            // Sessions with something running are still needed by someone.
+            let (idle, _) = named.into_iter()
+                .filter(|(_, session)| session.jobs.is_idle())
+                .min_by_key(|(_, session)| session.last_used)?;
+            self.drop_session(idle);
         }
+
+        Some(self.start_session(SessionKind::Named(name.to_vec())))
     }
 
-    // A little boilerplate
-    fn exec_file(&mut self) -> &mut RealizedFsEntry {
-        self.inodes.get_mut(&self.exec_file_inode)
-            .expect("Can't find exec file, should be impossible")
+    /// Starts a new session with its own shell.
+    fn start_session(&mut self, kind: SessionKind) -> SessionId {
+        let (command_sender, command_receiver) = mpsc::channel();
+        let (result_sender, result_receiver) = mpsc::channel();
+        let jobs = Arc::new(JobTable::new(self.config.spool()));
+
+        let (config, shell_jobs) = (self.config.clone(), jobs.clone());
+        thread::spawn(move || shell::run(config, shell_jobs, result_sender, command_receiver));
+
+        let dirs = (self.allocate_inode(), self.allocate_inode(), self.allocate_inode());
+        let id = self.make_session(kind, dirs, jobs, command_sender, result_receiver);
+        self.log(id, "Session started");
+        id
     }
 
-    fn wait_file(&mut self) -> &mut RealizedFsEntry {
-        self.inodes.get_mut(&self.wait_file_inode)
-            .expect("Can't find wait file, should be impossible")
+    /// Drops the sessions of processes which have exited. Their jobs are cancelled by now
+    /// anyway, and a new process with the same pid gets a session of its own.
+    fn drop_exited_sessions(&mut self) {
+        let exited = self.sessions.iter()
+            .filter(|(_, session)| matches!(&session.kind, SessionKind::Process(_, watch) if watch.has_exited()))
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+
+        for id in exited {
+            self.drop_session(id);
+        }
     }
 
-    fn reset_file(&mut self) -> &mut RealizedFsEntry {
-        self.inodes.get_mut(&self.reset_file_inode)
-            .expect("Can't find reset file, should be impossible")
+    /// Stops the shell of a session and removes it with all of its files.
+    /// Files still known to the kernel are gone for it as well.
+    fn drop_session(&mut self, id: SessionId) {
+        let session = self.sessions.remove(id).expect("Dropped session is gone, should be impossible");
+        // The shell terminates its jobs on its own, without holding up the filesystem.
+        let _ = session.command_channel.send(Command::Shutdown);
+        if let SessionKind::Named(name) = &session.kind {
+            self.notifier.inval_entry(SESSIONS_DIR_INODE, name);
+        }
+
+        self.inodes.retain(|&inode, file| !session.is_pinned(inode) && file.entry.session() != Some(id));
+        self.open_files.retain(|_, file| file.entry.session() != Some(id));
+        eprintln!("Dropped session {}", id);
     }
 
-    fn diagnostics_file(&mut self) -> &mut RealizedFsEntry {
-        self.inodes.get_mut(&self.diagnostics_file_inode)
-            .expect("Can't find diagnostics file, should be impossible")
+    /// The session a request made through the given session actually goes to.
+    /// With per-process sessions, everything done through the default session is done
+    /// in the session of the calling process instead, so that concurrent compilers
+    /// don't mix up their commands. Unless asked to, no session is started for
+    /// a process which doesn't have one yet, so that merely looking around doesn't start shells.
+    fn caller_session(&mut self, session: SessionId, pid: u32, uid: u32, start: bool) -> SessionId {
+        // Requests made by the kernel itself have no process.
+        if session != DEFAULT_SESSION || !self.config.sessions.per_process || pid == 0 {
+            return session;
+        }
+
+        self.drop_exited_sessions();
+
+        let process = process::thread_group(pid).unwrap_or(pid);
+        let owned = |session: &Session| matches!(session.kind, SessionKind::Process(owner, _) if owner == process);
+        if let Some(id) = self.sessions.find(owned) {
+            return id;
+        }
+
+        if !start {
+            return DEFAULT_SESSION;
+        }
+
+        let id = self.start_session(SessionKind::Process(process, ProcessWatch::new(process)));
+        self.log(id, &format!("Bound to process {} of user {}", process, uid));
+        id
     }
 
-    fn stdout_file(&mut self) -> &mut RealizedFsEntry {
-        self.inodes.get_mut(&self.stdout_file_inode)
-            .expect("Can't find stdout file, should be impossible")
+    /// The special files of the default session are shared between all processes, and
+    /// the kernel may cache them for longer than a process lives. Opening one
+    /// opens the same file of the session of the caller instead.
+    fn caller_file(&mut self, inode: u64, pid: u32, uid: u32) -> u64 {
+        let entries = self.sessions[DEFAULT_SESSION].dir_entries();
+        let Some(&(_, _, name)) = entries.iter().find(|&&(entry, kind, _)| entry == inode && kind == FileType::RegularFile) else {
+            return inode;
+        };
+
+        let session = self.caller_session(DEFAULT_SESSION, pid, uid, false);
+        self.sessions[session].dir_entries()
+            .into_iter()
+            .find(|&(_, _, entry)| entry == name)
+            .map_or(inode, |(inode, ..)| inode)
     }
 
-    fn stderr_file(&mut self) -> &mut RealizedFsEntry {
-        self.inodes.get_mut(&self.stderr_file_inode)
-            .expect("Can't find stderr file, should be impossible")
+    /// Finds out which directory of which session the inode is.
+    fn find_session_dir(&self, inode: u64) -> Option<(SessionId, SessionDir)> {
+        self.sessions.iter().find_map(|(id, session)| match inode {
+            _ if inode == session.dir_inode => Some((id, SessionDir::Root)),
+            _ if inode == session.run_dir_inode => Some((id, SessionDir::Run)),
+            _ if inode == session.jobs_dir_inode => Some((id, SessionDir::Jobs)),
+            _ => None,
+        })
     }
 
-    fn log_file(&mut self) -> &mut RealizedFsEntry {
-        self.inodes.get_mut(&self.log_file_inode)
-            .expect("Can't find log file, should be impossible")
+    /// The notifier has to be attached once the filesystem is mounted.
+    pub fn notifier(&self) -> Arc<Notifier> {
+        self.notifier.clone()
     }
 
-    /// Write a message to a log file. The buffer content is added to the message.
-    fn log(&mut self, message: &str) {
-        let message = format!(
-            "[buf={}] {}\n",
-            String::from_utf8_lossy(&self.decoded_command_buffer),
-            message
-        );
+    fn file(&mut self, inode: u64) -> &mut RealizedFsEntry {
+        self.inodes.get_mut(&inode).expect("Can't find special file, should be impossible")
+    }
 
-        self.log_file().append_result(message.as_bytes().to_vec());
+    /// Writes a result which grows with the output, so it goes to disk as it is generated
+    /// once it gets large. A result which fails to be written is left empty.
+    fn write_spooled(&mut self, session: SessionId, inode: u64, write: impl FnOnce(&mut OutputBuffer) -> io::Result<()>) {
+        let mut buffer = OutputBuffer::new(self.config.spool());
+        match write(&mut buffer) {
+            Ok(()) => self.file(inode).write_result(buffer.into_blob()),
+            Err(e) => {
+                self.log(session, &format!("Failed to write result: {}", e));
+                self.file(inode).write_result(Blob::default());
+            }
+        }
     }
 
-    /// Filesystem attributes of the root directory.
-    fn root_attrs(&self) -> FileAttr {
+    /// Write a message to the log file of a session. The buffer content is added to the message.
+    fn log(&mut self, session: SessionId, message: &str) {
+        let session = &self.sessions[session];
+        let message = log_line(&session.decoded_command_buffer, message);
+
+        let inode = session.log_file_inode;
+        self.file(inode).append_log(message.as_bytes());
+    }
+
+    /// Filesystem attributes of a directory.
+    fn dir_attrs(&self, inode: u64) -> FileAttr {
         FileAttr {
-            ino: 1,
+            ino: inode,
             size: 0,
             blocks: 0,
             atime: SystemTime::now(),
@@ -219,17 +781,153 @@ impl ShellEscapeFs {
         }
     }
 
+    /// Inodes are never reused, so that the kernel can't mix up an old file with a new one.
+    fn allocate_inode(&mut self) -> u64 {
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        inode
+    }
+
     /// Given a filesystem entry, adds it to the filesystem
     fn make_entry(&mut self, entry: FsEntry) -> &RealizedFsEntry {
-        let inode = self.inodes.len() as u64 + FILE_INODE_OFFSET;
-        let result = RealizedFsEntry {
-            inode: self.inodes.len() as u64 + FILE_INODE_OFFSET,
-            entry,
-        };
-        self.inodes.insert(inode, result);
+        let inode = self.allocate_inode();
+        self.inodes.insert(inode, RealizedFsEntry { inode, entry });
         self.inodes.get(&inode).expect("Can't find inode we just inserted")
     }
 
+    /// Replies to a lookup of a name which doesn't parse. The reason goes to the log,
+    /// so that typos don't go unnoticed.
+    fn reply_name_error(&mut self, session: SessionId, name: &[u8], error: NameError, reply: ReplyEntry) {
+        self.log(session, &format!("Invalid name {:?}: {}", String::from_utf8_lossy(name), error));
+        reply.error(error.errno());
+    }
+
+    /// Replies to a lookup with the given file, which the kernel holds on to until it forgets it.
+    fn reply_entry(&mut self, inode: u64, reply: ReplyEntry) {
+        let entry = self.get_entry(inode).expect("Can't find looked up entry, should be impossible");
+        let (ttl, attrs) = (self.ttl(entry), entry.get_attrs());
+        self.remember_lookup(inode);
+        reply.entry(&ttl, &attrs, 0);
+    }
+
+    fn remember_lookup(&mut self, inode: u64) {
+        *self.lookups.lock().unwrap().entry(inode).or_default() += 1;
+    }
+
+    /// Drops the given number of kernel references to a file, removing the file if it was the last one.
+    fn forget_lookups(&mut self, inode: u64, count: u64) {
+        let mut all_lookups = self.lookups.lock().unwrap();
+        let Some(lookups) = all_lookups.get_mut(&inode) else {
+            return;
+        };
+
+        *lookups = lookups.saturating_sub(count);
+        if *lookups == 0 {
+            all_lookups.remove(&inode);
+            drop(all_lookups);
+            if !self.is_pinned(inode) {
+                self.inodes.remove(&inode);
+            }
+        }
+    }
+
+    /// Whether the file has to stay even if the kernel doesn't know about it.
+    fn is_pinned(&self, inode: u64) -> bool {
+        self.sessions.values().any(|session| session.is_pinned(inode))
+    }
+
+    /// Removes every file which is not pinned and not known to the kernel,
+    /// such as files of jobs which were pruned from the job table.
+    /// Files still known to the kernel are removed once they are forgotten.
+    fn collect_garbage(&mut self) {
+        for session in self.sessions.values_mut() {
+            let (jobs, pinned, notifier) = (&session.jobs, &mut session.pinned, &self.notifier);
+            session.job_inodes.retain(|&id, inodes| {
+                let exists = jobs.get(id).is_some();
+                if !exists {
+                    notifier.inval_entry(session.jobs_dir_inode, id.to_string().as_bytes());
+                    for inode in inodes.all() {
+                        pinned.remove(&inode);
+                    }
+                }
+                exists
+            });
+        }
+
+        let lookups = self.lookups.lock().unwrap();
+        let garbage = self.inodes.keys()
+            .copied()
+            .filter(|inode| !lookups.contains_key(inode) && !self.is_pinned(*inode))
+            .collect::<Vec<_>>();
+        drop(lookups);
+
+        for inode in garbage {
+            self.inodes.remove(&inode);
+        }
+    }
+
+    /// Inodes of the files of a job. They are created on first use
+    /// and stay the same afterwards, so that a job can be watched with `tail -f`.
+    fn job_inodes(&mut self, session: SessionId, job: &Arc<LiveJob>) -> JobInodes {
+        if let Some(inodes) = self.sessions[session].job_inodes.get(&job.id) {
+            return *inodes;
+        }
+
+        let inodes = JobInodes {
+            dir: self.make_entry(FsEntry::JobDir(session, job.clone())).inode,
+            command: self.make_entry(FsEntry::ResultFile(job.command.as_slice().into())).inode,
+            stdout: self.make_entry(FsEntry::LiveOutputFile(job.clone(), LiveView::Stream(Stream::Stdout))).inode,
+            stderr: self.make_entry(FsEntry::LiveOutputFile(job.clone(), LiveView::Stream(Stream::Stderr))).inode,
+            output: self.make_entry(FsEntry::LiveOutputFile(job.clone(), LiveView::Combined(Rendering::Text))).inode,
+            output_json: self.make_entry(FsEntry::LiveOutputFile(job.clone(), LiveView::Combined(Rendering::Json))).inode,
+        };
+
+        let session = &mut self.sessions[session];
+        session.job_inodes.insert(job.id, inodes);
+        session.pinned.extend(inodes.all());
+        inodes
+    }
+
+    /// Looks up a job in `jobs/`.
+    fn lookup_job_dir(&mut self, session: SessionId, name: &[u8]) -> Option<&RealizedFsEntry> {
+        let id = std::str::from_utf8(name).ok()?.parse().ok()?;
+        let job = self.sessions[session].jobs.get(id)?;
+        let inode = self.job_inodes(session, &job).dir;
+        self.get_entry(inode)
+    }
+
+    /// Looks up a file inside `jobs/<id>/`.
+    fn lookup_job_entry(&mut self, parent: u64, name: &[u8]) -> Option<&RealizedFsEntry> {
+        let FsEntry::JobDir(session, job) = self.get_entry(parent)?.entry.clone() else {
+            return None;
+        };
+
+        let inodes = self.job_inodes(session, &job);
+        let inode = match name {
+            b"command" => inodes.command,
+            b"stdout" => inodes.stdout,
+            b"stderr" => inodes.stderr,
+            b"output" => inodes.output,
+            b"output.json" => inodes.output_json,
+            _ => return None,
+        };
+
+        self.get_entry(inode)
+    }
+
+    /// How long the kernel may cache a file and its name.
+    /// Files which grow in place change size all the time, and fresh files are made
+    /// for a single lookup, so they are never cached.
+    /// Special files and files of jobs stay in place and the kernel is notified
+    /// when they change or go away, other files are fresh on every lookup.
+    fn ttl(&self, entry: &RealizedFsEntry) -> Duration {
+        match &entry.entry {
+            FsEntry::LiveOutputFile(..) | FsEntry::LogFile(..) | FsEntry::FreshFile(..) => Duration::ZERO,
+            _ if self.is_pinned(entry.inode) => STABLE_TTL,
+            _ => TTL,
+        }
+    }
+
     /// Given an inode, returns the filesystem entry associated with it
     fn get_entry(&self, inode: u64) -> Option<&RealizedFsEntry> {
         self.inodes.get(&inode)
@@ -237,165 +935,498 @@ impl ShellEscapeFs {
 
     /// Takes the contents of the command buffer and sends it to the shell.
     /// The command buffer is cleared, the exec file is reset
-    fn do_exec(&mut self) {
-        println!("Execute: {:?}", self.decoded_command_buffer);
-        if self.decoded_command_buffer.is_empty() {
-            self.log("Ignoring execution because buffer is empty");
+    fn do_exec(&mut self, session: SessionId, pid: u32, key: Option<Vec<u8>>) {
+        println!("Execute: {:?}", self.sessions[session].decoded_command_buffer);
+        if self.sessions[session].decoded_command_buffer.is_empty() {
+            self.log(session, "Ignoring execution because buffer is empty");
+            return;
+        }
+
+        let command = std::mem::take(&mut self.sessions[session].decoded_command_buffer);
+        let job = Job { key, ..make_job(command, pid) };
+
+        // The shell still reports a result, so that waiting for the command tells why it didn't run.
+        if let Err(reason) = self.check_command(session, &job.command) {
+            self.sessions[session].command_channel.send(Command::Deny(job, reason)).expect("Failed to send command");
             return;
         }
 
-        self.log("Executing");
+        match &job.directory {
+            Some(directory) => self.log(session, &format!("Executing in {}", directory.display())),
+            None => self.log(session, "Executing"),
+        }
+
+        self.sessions[session].command_channel.send(Command::Execute(job)).expect("Failed to send command");
+    }
+
+    /// Whether the command may run according to the policy and, if approval is enabled,
+    /// the user. Asks the user if needed, which blocks the filesystem until they answer.
+    fn check_command(&mut self, session: SessionId, command: &[u8]) -> Result<(), String> {
+        if let Err(denial) = self.config.policy.check(command) {
+            self.log(session, &format!("Denied by policy: {}", denial));
+            return Err(denial.to_string());
+        }
+
+        if let Some(store) = self.config.trust_store() {
+            if let Err(reason) = store.approve(command) {
+                self.log(session, &format!("Not approved: {}", reason));
+                return Err(reason);
+            }
+        }
 
-        let command = std::mem::replace(&mut self.decoded_command_buffer, Vec::new());
-        self.command_channel.send(Command::Execute(command)).expect("Failed to send command");
+        Ok(())
+    }
 
-        self.exec_file_inode = self.make_entry(FsEntry::ExecFile()).inode;
+    /// Takes the contents of the command buffer as a manifest of commands
+    /// separated by zero bytes and asks the shell to start all of them ahead of time.
+    /// Later executions of the same commands will reuse the prefetched jobs.
+    fn do_prefetch(&mut self, session: SessionId, pid: u32) {
+        let manifest = std::mem::take(&mut self.sessions[session].decoded_command_buffer);
+        let queue = manifest
+            .split(|&c| c == 0)
+            .filter(|command| !command.is_empty())
+            .map(|command| make_job(command.to_vec(), pid))
+            .collect::<Vec<_>>();
+
+        // Denied commands are reported when they are executed, and approval is not asked
+        // for ahead of time either, the document may not even get to the command.
+        let trust_store = self.config.trust_store();
+        let (queue, denied): (Vec<_>, Vec<_>) = queue.into_iter().partition(|job| {
+            self.config.policy.check(&job.command).is_ok()
+                && trust_store.as_ref().is_none_or(|store| match store.lookup(&job.command) {
+                    Ok(decision) => decision == Some(Decision::Allow),
+                    Err(e) => {
+                        eprintln!("Failed to read {}: {}", store.path.display(), e);
+                        false
+                    }
+                })
+        });
+        if !denied.is_empty() {
+            self.log(session, &format!("Not prefetching {} commands which are not allowed yet", denied.len()));
+        }
+
+        self.log(session, &format!("Prefetching {} commands", queue.len()));
+        self.sessions[session].command_channel.send(Command::Prefetch(queue)).expect("Failed to send command");
     }
 
-    /// Waits for the shell to finish executing one command.
+    /// Waits for the shell of the session to finish executing one command.
     /// This blocks the entire filesystem, which is clearly not ideal,
     /// but it works for now.
     /// This can cause deadlock if the command being executed
     /// and waited for tries to access the filesystem.
     /// TODO: fix (not going to be easy though, so don't bother)
-    fn wait_one(&mut self) {
-        self.log("Waiting");
-
-        self.wait_file_inode = self.make_entry(FsEntry::WaitFile()).inode;
-
-        let result = self.results_channel.recv().expect("Failed to receive result");
-        self.log("Received result");
+    fn wait_one(&mut self, session: SessionId) {
+        self.log(session, "Waiting");
 
-        self.diagnostics_file_inode = self.make_entry(FsEntry::ResultFile(Vec::new())).inode;
-        self.stdout_file_inode = self.make_entry(FsEntry::ResultFile(Vec::new())).inode;
-        self.stderr_file_inode = self.make_entry(FsEntry::ResultFile(Vec::new())).inode;
+        let result = self.sessions[session].results_channel.recv().expect("Failed to receive result");
+        self.log(session, "Received result");
 
         let FinishedCommand::Execution(result) = result else {
             panic!("Received non-execution result");
         };
 
+        let results = self.sessions[session].results;
+
         let diagnostics_json = result.summarize_into_json().to_string().into_bytes();
-        self.diagnostics_file().write_result(diagnostics_json);
+        self.file(results.diagnostics).write_result(diagnostics_json);
+
+        // Everything but the diagnostics grows with the output, so it may go to disk as well.
+        self.write_spooled(session, results.result_json, |buffer| result.write_bundle_json(buffer));
+        self.write_spooled(session, results.result_cbor, |buffer| result.write_bundle_cbor(buffer));
 
-        if let ExecutionResult::Ran { stdout, stderr, .. } = result.result {
-            self.stdout_file().write_result(stdout);
-            self.stderr_file().write_result(stderr);
+        match result.combined_output() {
+            Some(combined) => {
+                // The live renderings of the job, which are already complete.
+                self.file(results.output).write_result(combined.text.clone());
+                self.file(results.output_json).write_result(combined.json.clone());
+            }
+            None => {
+                self.file(results.output).write_result(Blob::default());
+                self.file(results.output_json).write_result(b"[]".to_vec());
+            }
         }
+
+        let (stdout, stderr) = result.outputs();
+        self.file(results.stdout).write_result(stdout);
+        self.file(results.stderr).write_result(stderr);
+
+        self.invalidate_results(session);
     }
 
-    /// Terminates all running commands,
-    /// clears the command buffer, and resets every file
-    fn terminate_all(&mut self) {
-        self.command_channel.send(Command::TerminateAll).expect("Failed to send command");
-        self.log("Terminating");
+    /// Empties all the files with results of a command.
+    fn clear_results(&mut self, session: SessionId) {
+        for inode in self.sessions[session].results.all() {
+            self.file(inode).write_result(Blob::default());
+        }
+
+        self.invalidate_results(session);
+    }
+
+    fn invalidate_results(&self, session: SessionId) {
+        for inode in self.sessions[session].results.all() {
+            self.notifier.inval_inode(inode);
+        }
+    }
 
-        self.exec_file_inode = self.make_entry(FsEntry::ExecFile()).inode;
-        self.wait_file_inode = self.make_entry(FsEntry::WaitFile()).inode;
-        self.reset_file_inode = self.make_entry(FsEntry::ResetFile()).inode;
-        self.diagnostics_file_inode = self.make_entry(FsEntry::ResultFile(Vec::new())).inode;
-        self.stdout_file_inode = self.make_entry(FsEntry::ResultFile(Vec::new())).inode;
-        self.stderr_file_inode = self.make_entry(FsEntry::ResultFile(Vec::new())).inode;
+    /// Terminates all running commands of the session,
+    /// clears the command buffer, and resets every file
+    fn terminate_all(&mut self, session: SessionId) {
+        self.sessions[session].command_channel.send(Command::TerminateAll).expect("Failed to send command");
+        self.log(session, "Terminating");
 
-        self.decoded_command_buffer.clear();
+        self.clear_results(session);
+        self.sessions[session].decoded_command_buffer.clear();
 
         loop {
-            match self.results_channel.recv().expect("Failed to receive result") {
-                FinishedCommand::Termination => break,
-                _ => (),
+            let result = self.sessions[session].results_channel.recv().expect("Failed to receive result");
+            if let FinishedCommand::Termination = result {
+                break;
             }
         }
+
+        // Every run has finished by now, and the next lookups run the commands again.
+        let session = &mut self.sessions[session];
+        for (name, run) in std::mem::take(&mut session.runs) {
+            session.pinned.remove(&run.inode);
+            self.notifier.inval_entry(session.run_dir_inode, &name);
+        }
+
+        // Finished jobs were pruned from the table, their files can go as well.
+        self.collect_garbage();
+    }
+
+    /// Runs the given (hex-encoded) command on the first lookup of its name, bypassing the
+    /// command buffer, and answers the lookup with a file with the stdout of the command once it
+    /// finishes, or an errno if it did not succeed. Later lookups of the same name get the same
+    /// answer until the session is reset.
+    fn lookup_run(&mut self, session: SessionId, pid: u32, name: &[u8], encoded_bytes: Vec<u8>, reply: ReplyEntry) {
+        match self.start_run(session, pid, name, encoded_bytes) {
+            Ok(run) => run.reply(reply),
+            Err(errno) => reply.error(errno),
+        }
     }
 
-    /// Appends the given bytes (hex-encoded) to the command buffer
-    fn do_append(&mut self, encoded_bytes: Vec<u8>) {
-        self.decoded_command_buffer.append(&mut hex_decode(encoded_bytes));
-        self.log("Appended");
+    /// The run of the command looked up with the given name, started unless it already was.
+    fn start_run(&mut self, session: SessionId, pid: u32, name: &[u8], encoded_bytes: Vec<u8>) -> Result<Arc<Run>, i32> {
+        if let Some(run) = self.sessions[session].runs.get(name) {
+            return Ok(run.clone());
+        }
+
+        let command = hex_decode(encoded_bytes);
+        self.log(session, &format!("Running {:?}", String::from_utf8_lossy(&command)));
+
+        if self.check_command(session, &command).is_err() {
+            return Err(libc::EACCES);
+        }
+
+        let FsEntry::LogFile(log) = self.file(self.sessions[session].log_file_inode).entry.clone() else {
+            unreachable!("The log of a session is a log file");
+        };
+        let inode = self.allocate_inode();
+        let run = Arc::new(Run {
+            inode,
+            state: Mutex::new(RunState::Running(Vec::new())),
+            lookups: self.lookups.clone(),
+            log,
+            buffer: self.sessions[session].decoded_command_buffer.clone(),
+        });
+        self.inodes.insert(inode, RealizedFsEntry { inode, entry: FsEntry::RunOutput(run.clone()) });
+        self.sessions[session].runs.insert(name.to_vec(), run.clone());
+        self.sessions[session].pinned.insert(run.inode);
+
+        let finished = run.clone();
+        let report = Box::new(move |result| finished.finish(result));
+        self.sessions[session].command_channel.send(Command::Run(make_job(command, pid), report)).expect("Failed to send command");
+        Ok(run)
+    }
+
+    /// Creates a fresh file containing a random hex string.
+    fn make_random_file(&mut self) -> &RealizedFsEntry {
+        let mut bytes = [0; RANDOM_BYTES];
+        std::fs::File::open("/dev/urandom")
+            .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+            .expect("Failed to read from /dev/urandom");
+
+        self.make_entry(FsEntry::FreshFile(hex_encode(&bytes).into()))
+    }
+
+    /// Creates a fresh file containing the next value of the counter.
+    fn make_nonce_file(&mut self) -> &RealizedFsEntry {
+        let nonce = self.nonce;
+        self.nonce += 1;
+        self.make_entry(FsEntry::FreshFile(nonce.to_string().into_bytes().into()))
+    }
+
+    /// Creates a fresh file with statistics about the filesystem itself.
+    fn make_stats_file(&mut self) -> &RealizedFsEntry {
+        let stats = serde_json::json!({
+            "inodes": self.inodes.len(),
+            "kernel_references": self.lookups.lock().unwrap().values().sum::<u64>(),
+            "open_files": self.open_files.len(),
+            "sessions": self.sessions.len(),
+            "jobs": self.sessions.values().map(|session| session.jobs.ids().len()).sum::<usize>(),
+        });
+        self.make_entry(FsEntry::FreshFile(stats.to_string().into_bytes().into()))
+    }
+
+    /// Appends the given bytes (hex-encoded) to the command buffer of the session
+    fn do_append(&mut self, session: SessionId, encoded_bytes: Vec<u8>) {
+        self.sessions[session].decoded_command_buffer.append(&mut hex_decode(encoded_bytes));
+        self.log(session, "Appended");
     }
 }
 
-fn is_allowed_char(c: u8) -> bool {
-    match c {
-        b'a'..=b'f' | b'0'..=b'9' => true,
-        _ => false,
+impl ShellEscapeFs {
+    /// Looks up a file in the directory of a session.
+    fn lookup_session_file(&mut self, session: SessionId, name: &[u8], reply: ReplyEntry) {
+        let action = match names::parse_root(name) {
+            Ok(action) => action,
+            Err(e) => return self.reply_name_error(session, name, e, reply),
+        };
+
+        let inode = match action {
+            Action::CurrentDir => return reply.entry(&TTL, &self.dir_attrs(self.sessions[session].dir_inode), 0),
+            Action::RunDir => return reply.entry(&TTL, &self.dir_attrs(self.sessions[session].run_dir_inode), 0),
+            Action::JobsDir => return reply.entry(&TTL, &self.dir_attrs(self.sessions[session].jobs_dir_inode), 0),
+            // Sessions don't nest.
+            Action::SessionsDir if session == DEFAULT_SESSION =>
+                return reply.entry(&TTL, &self.dir_attrs(SESSIONS_DIR_INODE), 0),
+            Action::SessionsDir => return reply.error(libc::ENOENT),
+            Action::Exec => self.sessions[session].exec_file_inode,
+            Action::ExecKeyed(key) => self.make_entry(FsEntry::KeyedExecFile(session, key)).inode,
+            Action::Wait => self.sessions[session].wait_file_inode,
+            Action::Reset => self.sessions[session].reset_file_inode,
+            Action::Prefetch => self.sessions[session].prefetch_file_inode,
+            Action::Diagnostics => self.sessions[session].results.diagnostics,
+            Action::Stdout => self.sessions[session].results.stdout,
+            Action::Stderr => self.sessions[session].results.stderr,
+            Action::ResultJson => self.sessions[session].results.result_json,
+            Action::ResultCbor => self.sessions[session].results.result_cbor,
+            Action::Output => self.sessions[session].results.output,
+            Action::OutputJson => self.sessions[session].results.output_json,
+            Action::Log => self.sessions[session].log_file_inode,
+            Action::Random => self.make_random_file().inode,
+            Action::Nonce => self.make_nonce_file().inode,
+            Action::Stats => self.make_stats_file().inode,
+            Action::Sleep(duration) => self.make_entry(FsEntry::SleepFile(duration)).inode,
+            Action::Append(encoded_bytes) => self.make_entry(FsEntry::AppendDataFile(session, encoded_bytes)).inode,
+        };
+
+        self.reply_entry(inode, reply);
+    }
+
+    /// Reads from an open file on behalf of the given thread and user.
+    fn read_file(&mut self, fh: u64, offset: i64, size: u32, pid: u32, uid: u32, reply: impl DataReply) {
+        let Some(open_file) = self.open_files.get_mut(&fh) else {
+            reply.error(libc::EBADF);
+            return;
+        };
+
+        // Actions happen once per open, whatever offsets the file is read at.
+        let first_read = !std::mem::replace(&mut open_file.read, true);
+        let entry = open_file.entry.clone();
+
+        let slice = offset as usize..offset as usize + size as usize;
+
+        match entry {
+            FsEntry::ExecFile(session) => {
+                if first_read {
+                    let session = self.caller_session(session, pid, uid, true);
+                    self.do_exec(session, pid, None);
+                }
+                reply.data(clip(SUCCESS_MESSAGE, slice));
+            }
+            FsEntry::KeyedExecFile(session, key) => {
+                if first_read {
+                    let session = self.caller_session(session, pid, uid, true);
+                    self.do_exec(session, pid, Some(key));
+                }
+                reply.data(clip(SUCCESS_MESSAGE, slice));
+            }
+            FsEntry::WaitFile(session) => {
+                if first_read {
+                    let session = self.caller_session(session, pid, uid, true);
+                    self.wait_one(session);
+                }
+                reply.data(clip(SUCCESS_MESSAGE, slice));
+            }
+            FsEntry::ResetFile(session) => {
+                if first_read {
+                    let session = self.caller_session(session, pid, uid, true);
+                    self.terminate_all(session);
+                }
+                reply.data(clip(SUCCESS_MESSAGE, slice));
+            }
+            FsEntry::PrefetchFile(session) => {
+                if first_read {
+                    let session = self.caller_session(session, pid, uid, true);
+                    self.do_prefetch(session, pid);
+                }
+                reply.data(clip(SUCCESS_MESSAGE, slice));
+            }
+            FsEntry::SleepFile(duration) if first_read => {
+                // Replying later lets the filesystem serve other requests while this one is pending.
+                self.timer.after(duration, move || reply.data(clip(SUCCESS_MESSAGE, slice)));
+            }
+            FsEntry::SleepFile(_) => reply.data(clip(SUCCESS_MESSAGE, slice)),
+
+            FsEntry::AppendDataFile(session, encoded_bytes) => {
+                if first_read {
+                    let session = self.caller_session(session, pid, uid, true);
+                    self.do_append(session, encoded_bytes);
+                }
+                reply.data(clip(SUCCESS_MESSAGE, slice));
+            }
+            FsEntry::ResultFile(data) | FsEntry::FreshFile(data) => reply_data(data.read_range(slice), reply),
+            FsEntry::LogFile(log) => reply.data(clip(log.lock().unwrap().contents(), slice)),
+            FsEntry::LiveOutputFile(job, LiveView::Stream(stream)) => {
+                let data = job.output(stream).lock().unwrap();
+                reply_data(data.read_range(slice), reply);
+            }
+            FsEntry::LiveOutputFile(job, LiveView::Combined(rendering)) => {
+                reply_data(job.read_combined(rendering, slice), reply);
+            }
+            FsEntry::RunOutput(run) => reply_data(run.output().unwrap_or_default().read_range(slice), reply),
+            FsEntry::JobDir(..) => reply.error(libc::EISDIR),
+        }
     }
 }
 
-/// Takes a subrange of a given slice and runs the given function on it if it's not empty,
-/// returning the subrange
-fn and_if_not_empty<T>(slice: &[T], range: Range<usize>, f: impl FnOnce(&[T])) -> &[T] {
-    let clipped_range = range.start..range.end.min(slice.len());
-    let clipped = &slice[clipped_range.clone()];
-    if !clipped_range.is_empty() {
-        f(clipped);
+/// A line of the log of a session, along with the content of its command buffer.
+fn log_line(buffer: &[u8], message: &str) -> String {
+    format!("[buf={}] {}\n", String::from_utf8_lossy(buffer), message)
+}
+
+/// A job for a command asked for by the given thread, zero if it is not known.
+/// It runs in the project directory of the process and lives as long as the process does.
+fn make_job(command: Vec<u8>, pid: u32) -> Job {
+    Job {
+        command,
+        directory: process::project_directory(pid),
+        owner: process::thread_group(pid),
+        key: None,
     }
-    clipped
 }
 
-impl Filesystem for ShellEscapeFs {
-    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        assert_eq!(parent, 1, "Parent inode must be root, should be impossible");
-        eprintln!("Lookup: {:?}", name);
+/// Where the data of a read goes, a `ReplyData` outside of tests.
+trait DataReply: Send + 'static {
+    fn data(self, data: &[u8]);
+    fn error(self, err: libc::c_int);
+}
 
-        let name = name.as_bytes();
+impl DataReply for ReplyData {
+    fn data(self, data: &[u8]) {
+        ReplyData::data(self, data);
+    }
 
-        let name = if let Some((start_of_name, _)) = name.iter().enumerate().rfind(|(_, &c)| c == b'_') {
-            &name[start_of_name + 1..]
-        } else {
-            name
-        };
+    fn error(self, err: libc::c_int) {
+        ReplyData::error(self, err);
+    }
+}
 
-        if name == b"." {
-            return reply.entry(&TTL, &self.root_attrs(), 0);
+/// Replies to a read with the data, or with an I/O error if it couldn't be read back.
+fn reply_data(data: io::Result<impl Deref<Target = [u8]>>, reply: impl DataReply) {
+    match data {
+        Ok(data) => reply.data(&data),
+        Err(e) => {
+            eprintln!("Failed to read output: {}", e);
+            reply.error(libc::EIO);
         }
+    }
+}
 
-        let name = if let Some((end_of_name, _)) = name.iter().enumerate().rfind(|(_, &c)| c == b'.') {
-            &name[..end_of_name]
-        } else {
-            name
-        };
+/// Takes a subrange of a given slice, clipped to its length
+fn clip<T>(slice: &[T], range: Range<usize>) -> &[T] {
+    &slice[range.start.min(slice.len())..range.end.min(slice.len())]
+}
+
+impl Filesystem for ShellEscapeFs {
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        eprintln!("Lookup: {} {:?}", parent, name);
+
+        let name = name.as_bytes();
+
+        if parent == SESSIONS_DIR_INODE {
+            return match names::parse_session(name) {
+                Ok(name) => match self.get_or_make_session(name) {
+                    Some(session) => reply.entry(&TTL, &self.dir_attrs(self.sessions[session].dir_inode), 0),
+                    None => {
+                        self.log(DEFAULT_SESSION, "Too many sessions, and all of them are busy");
+                        reply.error(libc::ENOSPC)
+                    }
+                },
+                Err(e) => self.reply_name_error(DEFAULT_SESSION, name, e, reply),
+            };
+        }
 
-        let reply_entry = |entry: Option<&RealizedFsEntry>| {
-            match entry {
-                Some(entry) => reply.entry(&TTL, &entry.get_attrs(), 0),
+        let Some((session, dir)) = self.find_session_dir(parent) else {
+            return match self.lookup_job_entry(parent, name).map(|entry| entry.inode) {
+                Some(inode) => self.reply_entry(inode, reply),
                 None => reply.error(libc::ENOENT),
-            }
+            };
         };
 
-        match name {
-            b"exec" => reply_entry(Some(&self.exec_file())),
-            b"wait" => reply_entry(Some(&self.wait_file())),
-            b"reset" => reply_entry(Some(&self.reset_file())),
-            b"diagnostics" => reply_entry(Some(&self.diagnostics_file())),
-            b"stdout" => reply_entry(Some(&self.stdout_file())),
-            b"stderr" => reply_entry(Some(&self.stderr_file())),
-            b"log" => reply_entry(Some(&self.log_file())),
-
-            x if x.iter().all(|&c| is_allowed_char(c)) => {
-                let fs_entry = FsEntry::AppendDataFile(x.into());
-                reply_entry(Some(&self.make_entry(fs_entry)))
-            }
+        match dir {
+            SessionDir::Root => self.lookup_session_file(session, name, reply),
+            SessionDir::Run => {
+                let session = self.caller_session(session, req.pid(), req.uid(), true);
+                let command = match names::parse_run(name) {
+                    Ok(command) => command,
+                    Err(e) => return self.reply_name_error(session, name, e, reply),
+                };
 
-            _ => reply_entry(None),
+                self.lookup_run(session, req.pid(), name, command.to_vec(), reply);
+            }
+            SessionDir::Jobs => {
+                let session = self.caller_session(session, req.pid(), req.uid(), false);
+                match self.lookup_job_dir(session, name).map(|entry| entry.inode) {
+                    Some(inode) => self.reply_entry(inode, reply),
+                    None => reply.error(libc::ENOENT),
+                }
+            }
         }
     }
 
-    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        eprintln!("Forget: {} {}", ino, nlookup);
+        self.forget_lookups(ino, nlookup);
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
         eprintln!("Getattr: {}", ino);
 
-        if ino == 1 {
-            reply.attr(&TTL, &self.root_attrs());
+        if ino == SESSIONS_DIR_INODE || self.find_session_dir(ino).is_some() {
+            reply.attr(&TTL, &self.dir_attrs(ino));
         } else if let Some(entry) = self.get_entry(ino) {
-            reply.attr(&TTL, &entry.get_attrs());
+            reply.attr(&self.ttl(entry), &entry.get_attrs());
         } else {
             reply.error(libc::ENOENT);
         }
     }
 
+    fn open(&mut self, req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        eprintln!("Open: {}", ino);
+
+        let ino = self.caller_file(ino, req.pid(), req.uid());
+
+        let Some(RealizedFsEntry { entry, .. }) = self.get_entry(ino).cloned() else {
+            return reply.error(libc::ENOENT);
+        };
+
+        let fh = self.next_file_handle;
+        self.next_file_handle += 1;
+        self.open_files.insert(fh, OpenFile { entry, read: false });
+
+        // Reads always come to us, so the page cache never serves stale data,
+        // and the size reported by getattr doesn't limit how much can be read.
+        reply.opened(fh, FOPEN_DIRECT_IO);
+    }
+
     fn read(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         _flags: i32,
@@ -404,65 +1435,93 @@ impl Filesystem for ShellEscapeFs {
     ) {
         eprintln!("Read: {} {} {}", ino, offset, size);
 
-        let Some(RealizedFsEntry { entry, .. }) = self.get_entry(ino).cloned() else {
-            reply.error(libc::ENOENT);
-            return;
-        };
-
-        let slice = offset as usize..offset as usize + size as usize;
-
-        match entry {
-            FsEntry::ExecFile() => {
-                reply.data(and_if_not_empty(
-                    SUCCESS_MESSAGE,
-                    slice,
-                    |_| self.do_exec(),
-                ));
-            }
-            FsEntry::WaitFile() => {
-                reply.data(and_if_not_empty(
-                    SUCCESS_MESSAGE,
-                    slice,
-                    |_| self.wait_one(),
-                ));
-            }
-            FsEntry::ResetFile() => {
-                reply.data(and_if_not_empty(
-                    SUCCESS_MESSAGE,
-                    slice,
-                    |_| self.terminate_all(),
-                ));
-            }
+        self.read_file(fh, offset, size, req.pid(), req.uid(), reply);
+    }
 
-            FsEntry::AppendDataFile(encoded_bytes) => {
-                reply.data(and_if_not_empty(
-                    SUCCESS_MESSAGE,
-                    slice,
-                    |_| self.do_append(encoded_bytes),
-                ));
-            }
-            FsEntry::ResultFile(data) => reply.data(and_if_not_empty(&data, slice, |_| ())),
-        }
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        eprintln!("Release: {} {}", ino, fh);
+        self.open_files.remove(&fh);
+        reply.ok();
     }
 
-    fn readdir(&mut self, _req: &Request<'_>, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectory) {
-        assert_eq!(ino, 1, "Inode must be root, should be impossible");
+    fn readdir(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectory) {
         assert_eq!(fh, 0, "File handle must be 0, should be impossible");
-        eprintln!("Readdir: {}", offset);
+        eprintln!("Readdir: {} {}", ino, offset);
+
+        let entries = if ino == SESSIONS_DIR_INODE {
+            let mut entries = vec![
+                (SESSIONS_DIR_INODE, FileType::Directory, ".".to_string()),
+                (ROOT_DIR_INODE, FileType::Directory, "..".to_string()),
+            ];
+
+            for session in self.sessions.values() {
+                if let SessionKind::Named(name) = &session.kind {
+                    let name = String::from_utf8_lossy(name).into_owned();
+                    entries.push((session.dir_inode, FileType::Directory, name));
+                }
+            }
 
-        let entries = [
-            (1, FileType::Directory, "."),
-            (1, FileType::Directory, ".."),
-            (self.exec_file_inode, FileType::RegularFile, "exec"),
-            (self.wait_file_inode, FileType::RegularFile, "wait"),
-            (self.reset_file_inode, FileType::RegularFile, "reset"),
-            (self.diagnostics_file_inode, FileType::RegularFile, "diagnostics"),
-            (self.stdout_file_inode, FileType::RegularFile, "stdout"),
-            (self.stderr_file_inode, FileType::RegularFile, "stderr"),
-            (self.log_file_inode, FileType::RegularFile, "log"),
-        ];
+            entries
+        } else if let Some((session, dir)) = self.find_session_dir(ino) {
+            let (dir_inode, run_dir_inode, jobs_dir_inode) = {
+                let session = &self.sessions[session];
+                (session.dir_inode, session.run_dir_inode, session.jobs_dir_inode)
+            };
+
+            match dir {
+                SessionDir::Root => self.sessions[session].dir_entries()
+                    .into_iter()
+                    .map(|(inode, kind, name)| (inode, kind, name.to_string()))
+                    .collect(),
+                // Commands can't be listed, they are run on lookup.
+                SessionDir::Run => vec![
+                    (run_dir_inode, FileType::Directory, ".".to_string()),
+                    (dir_inode, FileType::Directory, "..".to_string()),
+                ],
+                SessionDir::Jobs => {
+                    let mut entries = vec![
+                        (jobs_dir_inode, FileType::Directory, ".".to_string()),
+                        (dir_inode, FileType::Directory, "..".to_string()),
+                    ];
+
+                    let session = self.caller_session(session, req.pid(), req.uid(), false);
+                    let jobs = self.sessions[session].jobs.clone();
+                    for id in jobs.ids() {
+                        if let Some(job) = jobs.get(id) {
+                            entries.push((self.job_inodes(session, &job).dir, FileType::Directory, id.to_string()));
+                        }
+                    }
+
+                    entries
+                }
+            }
+        } else {
+            let Some(RealizedFsEntry { entry: FsEntry::JobDir(session, job), .. }) = self.get_entry(ino).cloned() else {
+                return reply.error(libc::ENOTDIR);
+            };
+
+            let inodes = self.job_inodes(session, &job);
+            vec![
+                (inodes.dir, FileType::Directory, ".".to_string()),
+                (self.sessions[session].jobs_dir_inode, FileType::Directory, "..".to_string()),
+                (inodes.command, FileType::RegularFile, "command".to_string()),
+                (inodes.stdout, FileType::RegularFile, "stdout".to_string()),
+                (inodes.stderr, FileType::RegularFile, "stderr".to_string()),
+                (inodes.output, FileType::RegularFile, "output".to_string()),
+                (inodes.output_json, FileType::RegularFile, "output.json".to_string()),
+            ]
+        };
 
-        for (dir_offset, (inode, kind, name)) in entries.iter().skip(offset as usize).enumerate() {
+        for (dir_offset, (inode, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
             let reported_offset = dir_offset as i64 + 1;
 
             let full = reply.add(*inode, reported_offset, *kind, name);
@@ -474,3 +1533,277 @@ impl Filesystem for ShellEscapeFs {
         reply.ok();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::*;
+
+    /// Reading a file in chunks used to clone its entire content for every chunk.
+    /// Counts the bytes read.
+    struct CountingReply(Arc<AtomicUsize>);
+
+    impl DataReply for CountingReply {
+        fn data(self, data: &[u8]) {
+            self.0.fetch_add(data.len(), Ordering::Relaxed);
+        }
+
+        fn error(self, err: libc::c_int) {
+            panic!("Read failed: {}", err);
+        }
+    }
+
+    /// A filesystem whose default session has no shell behind it. The other ends of
+    /// its channels are returned too, so that they stay open as long as needed.
+    fn filesystem(config: Config) -> (ShellEscapeFs, (mpsc::Receiver<Command>, mpsc::Sender<FinishedCommand>)) {
+        let (command_sender, command_receiver) = mpsc::channel();
+        let (result_sender, result_receiver) = mpsc::channel();
+        let fs = ShellEscapeFs::new(Arc::new(config), Arc::new(JobTable::default()), command_sender, result_receiver);
+        (fs, (command_receiver, result_sender))
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture bench_result_file_reads`,
+    /// time per megabyte should stay the same as the file grows.
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_result_file_reads() {
+        const CHUNK_SIZE: usize = 128 * 1024;
+
+        let (mut fs, _channels) = filesystem(Config::default());
+
+        for megabytes in [4, 16, 64] {
+            let data = vec![b'x'; megabytes * 1024 * 1024];
+            fs.open_files.insert(1, OpenFile { entry: FsEntry::ResultFile(data.into()), read: false });
+
+            let total = Arc::new(AtomicUsize::new(0));
+            let start = std::time::Instant::now();
+            for offset in (0..megabytes * 1024 * 1024).step_by(CHUNK_SIZE) {
+                fs.read_file(1, offset as i64, CHUNK_SIZE as u32, 0, 0, CountingReply(total.clone()));
+            }
+            let elapsed = start.elapsed();
+
+            assert_eq!(total.load(Ordering::Relaxed), megabytes * 1024 * 1024);
+            println!(
+                "{:>3} MiB: {:>10.3?} total, {:>10.3?} per MiB",
+                megabytes,
+                elapsed,
+                elapsed / megabytes as u32,
+            );
+        }
+    }
+
+    #[test]
+    fn test_collect_garbage() {
+        let (mut fs, _channels) = filesystem(Config::default());
+        let special_files = fs.inodes.len();
+
+        // Looked up files stay until the kernel forgets all the references to them.
+        let append = fs.make_entry(FsEntry::AppendDataFile(DEFAULT_SESSION, b"6c73".to_vec())).inode;
+        fs.remember_lookup(append);
+        fs.remember_lookup(append);
+        fs.collect_garbage();
+        fs.forget_lookups(append, 1);
+        assert!(fs.get_entry(append).is_some());
+        fs.forget_lookups(append, 1);
+        assert!(fs.get_entry(append).is_none());
+
+        // Files the kernel never saw go away right away.
+        let unseen = fs.make_random_file().inode;
+        fs.collect_garbage();
+        assert!(fs.get_entry(unseen).is_none());
+
+        // Current special files are never removed.
+        let exec_file_inode = fs.sessions[DEFAULT_SESSION].exec_file_inode;
+        fs.remember_lookup(exec_file_inode);
+        fs.forget_lookups(exec_file_inode, 1);
+        assert!(fs.get_entry(exec_file_inode).is_some());
+        assert_eq!(fs.inodes.len(), special_files);
+    }
+
+    #[test]
+    fn test_ttl() {
+        let (mut fs, _channels) = filesystem(Config::default());
+
+        // Every lookup of these has to come to the filesystem to get a new value.
+        for entry in [fs.make_random_file().clone(), fs.make_nonce_file().clone(), fs.make_stats_file().clone()] {
+            assert_eq!(fs.ttl(&entry), Duration::ZERO);
+        }
+        let result = fs.make_entry(FsEntry::ResultFile(Blob::default())).clone();
+        assert_eq!(fs.ttl(&result), TTL);
+        let exec_file = fs.get_entry(fs.sessions[DEFAULT_SESSION].exec_file_inode).unwrap().clone();
+        assert_eq!(fs.ttl(&exec_file), STABLE_TTL);
+    }
+
+    #[test]
+    fn test_log_limit() {
+        let mut log = Log::default();
+        log.append(b"first\n");
+        assert_eq!(log.contents(), b"first\n");
+
+        let line = [b'x'; 999];
+        for _ in 0..2000 {
+            log.append(&line);
+            log.append(b"\n");
+        }
+        assert!(log.len() <= LOG_LIMIT);
+        assert!(log.len() > LOG_LIMIT - 1000);
+        assert!(log.contents().starts_with(&line));
+        assert!(log.contents().chunks(1000).all(|chunk| chunk[..999] == line && chunk[999] == b'\n'));
+    }
+
+    #[test]
+    fn test_sessions() {
+        let (mut fs, _channels) = filesystem(Config::default());
+
+        let first = fs.get_or_make_session(b"first").unwrap();
+        let second = fs.get_or_make_session(b"second").unwrap();
+        assert_eq!(fs.get_or_make_session(b"first").unwrap(), first);
+        assert_eq!(fs.find_session_dir(fs.sessions[second].jobs_dir_inode), Some((second, SessionDir::Jobs)));
+        assert_eq!(fs.find_session_dir(ROOT_DIR_INODE), Some((DEFAULT_SESSION, SessionDir::Root)));
+
+        // Every session has its own buffer, shell and results.
+        fs.do_append(first, hex_encode(b"echo one"));
+        fs.do_append(second, hex_encode(b"echo two"));
+        fs.do_exec(first, 0, None);
+        fs.do_exec(second, 0, None);
+        fs.wait_one(second);
+        fs.wait_one(first);
+
+        let stdout = |fs: &ShellEscapeFs, session: SessionId| {
+            let Some(RealizedFsEntry { entry: FsEntry::ResultFile(data), .. }) =
+                fs.get_entry(fs.sessions[session].results.stdout) else {
+                unreachable!();
+            };
+            data.to_vec().unwrap()
+        };
+        assert_eq!(stdout(&fs, first), b"one\n");
+        assert_eq!(stdout(&fs, second), b"two\n");
+        assert!(fs.sessions[DEFAULT_SESSION].decoded_command_buffer.is_empty());
+
+        // Resetting one session leaves the others alone.
+        fs.terminate_all(first);
+        assert!(stdout(&fs, first).is_empty());
+        assert_eq!(stdout(&fs, second), b"two\n");
+    }
+
+    #[test]
+    fn test_runs() {
+        let (mut fs, _channels) = filesystem(Config::default());
+        let session = fs.get_or_make_session(b"runs").unwrap();
+
+        // Runs go through the shell of the session, and the same name gets the same run.
+        let run = fs.start_run(session, 0, b"a_6563686f206869", hex_encode(b"echo hi")).unwrap();
+        assert!(Arc::ptr_eq(&run, &fs.start_run(session, 0, b"a_6563686f206869", hex_encode(b"echo hi")).unwrap()));
+        assert!(!Arc::ptr_eq(&run, &fs.start_run(session, 0, b"b_6563686f206869", hex_encode(b"echo hi")).unwrap()));
+        let start = std::time::Instant::now();
+        while run.output().is_none() {
+            assert!(start.elapsed() < Duration::from_secs(10), "Run didn't finish");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(run.output().unwrap().to_vec().unwrap(), b"hi\n");
+
+        // Resetting the session terminates runs and forgets them.
+        let sleeping = fs.start_run(session, 0, b"736c656570203130", hex_encode(b"sleep 10")).unwrap();
+        fs.terminate_all(session);
+        assert!(matches!(*sleeping.state.lock().unwrap(), RunState::Finished(Err(libc::EIO))));
+        assert!(fs.sessions[session].runs.is_empty());
+        assert!(fs.get_entry(sleeping.inode).is_none());
+    }
+
+    #[test]
+    fn test_policy() {
+        let config: Config = serde_json::from_str(r#"{"policy": {"default": "deny"}}"#).unwrap();
+        let (mut fs, _channels) = filesystem(config);
+        let session = fs.get_or_make_session(b"policy").unwrap();
+
+        fs.do_append(session, hex_encode(b"echo hi"));
+        fs.do_exec(session, 0, None);
+        fs.wait_one(session);
+
+        let Some(RealizedFsEntry { entry: FsEntry::ResultFile(data), .. }) =
+            fs.get_entry(fs.sessions[session].results.diagnostics) else {
+            unreachable!();
+        };
+        let diagnostics: serde_json::Value = serde_json::from_slice(&data.to_vec().unwrap()).unwrap();
+        assert_eq!(diagnostics["result"]["error"], "Denied by policy");
+        assert!(fs.start_run(session, 0, b"6563686f206869", hex_encode(b"echo hi")).is_err_and(|errno| errno == libc::EACCES));
+    }
+
+    #[test]
+    fn test_caller_session() {
+        let (mut fs, _channels) = filesystem(Config::default());
+
+        let pid = std::process::id();
+        // SAFETY: gettid has no memory safety requirements.
+        let tid = unsafe { libc::gettid() } as u32;
+
+        // Looking around doesn't start a session, the kernel and explicit sessions aren't redirected.
+        assert_eq!(fs.caller_session(DEFAULT_SESSION, pid, 0, false), DEFAULT_SESSION);
+        assert_eq!(fs.caller_session(DEFAULT_SESSION, 0, 0, true), DEFAULT_SESSION);
+        let named = fs.get_or_make_session(b"named").unwrap();
+        assert_eq!(fs.caller_session(named, pid, 0, true), named);
+
+        // All threads of a process share a session.
+        let session = fs.caller_session(DEFAULT_SESSION, tid, 0, true);
+        assert_ne!(session, DEFAULT_SESSION);
+        assert!(matches!(fs.sessions[session].kind, SessionKind::Process(process, _) if process == pid));
+        assert_eq!(fs.caller_session(DEFAULT_SESSION, pid, 0, false), session);
+
+        // Sessions of processes can't be reached by name.
+        assert_ne!(fs.get_or_make_session(format!("process-{}", pid).as_bytes()).unwrap(), session);
+
+        // Special files of the root are opened in the session of the caller.
+        assert_eq!(fs.caller_file(fs.sessions[DEFAULT_SESSION].exec_file_inode, pid, 0), fs.sessions[session].exec_file_inode);
+        assert_eq!(fs.caller_file(fs.sessions[DEFAULT_SESSION].log_file_inode, 0, 0), fs.sessions[DEFAULT_SESSION].log_file_inode);
+        assert_eq!(fs.caller_file(RUN_DIR_INODE, pid, 0), RUN_DIR_INODE);
+    }
+
+    #[test]
+    fn test_session_limit() {
+        let mut config = Config::default();
+        config.sessions.max_named = 2;
+        let (mut fs, _channels) = filesystem(config);
+
+        let first = fs.get_or_make_session(b"first").unwrap();
+        let second = fs.get_or_make_session(b"second").unwrap();
+        fs.get_or_make_session(b"first").unwrap();
+
+        // The least recently used session makes room.
+        let third = fs.get_or_make_session(b"third").unwrap();
+        assert!(fs.sessions.iter().all(|(id, _)| id != second));
+        assert_eq!(fs.sessions.len(), 3);
+
+        // Sessions with something running stay.
+        let busy = fs.sessions[first].jobs.register(b"sleep 10".to_vec());
+        fs.sessions[third].jobs.register(b"sleep 10".to_vec());
+        assert_eq!(fs.get_or_make_session(b"fourth"), None);
+
+        busy.finish();
+        fs.get_or_make_session(b"fourth").unwrap();
+        assert!(fs.sessions.iter().all(|(id, _)| id != first));
+        assert_eq!(fs.get_or_make_session(b"third"), Some(third));
+    }
+
+    #[test]
+    fn test_dropped_sessions() {
+        let (mut fs, _channels) = filesystem(Config::default());
+
+        let mut child = std::process::Command::new("sleep").arg("10").spawn().unwrap();
+        let session = fs.caller_session(DEFAULT_SESSION, child.id(), 0, true);
+        let exec_file_inode = fs.sessions[session].exec_file_inode;
+        fs.open_files.insert(1, OpenFile { entry: FsEntry::ExecFile(session), read: false });
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        // The next request through the root notices, whoever makes it.
+        assert_eq!(fs.caller_session(DEFAULT_SESSION, std::process::id(), 0, false), DEFAULT_SESSION);
+        assert!(fs.sessions.iter().all(|(id, _)| id != session));
+        assert!(fs.get_entry(exec_file_inode).is_none());
+        assert!(fs.open_files.is_empty());
+
+        // A new process with the same pid would get a new session.
+        let again = fs.caller_session(DEFAULT_SESSION, child.id(), 0, true);
+        assert_ne!(again, session);
+    }
+}
@@ -0,0 +1,331 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde_json::json;
+use crate::buffer::{Blob, OutputBuffer, ReadRange, Spool};
+
+/// One of the output streams of a job.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    pub fn name(self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+/// How the output of both streams is shown, interleaved in the order it was read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rendering {
+    /// Plain text.
+    Text,
+    /// A JSON array of the chunks, with the stream and time of each.
+    Json,
+}
+
+/// A piece of output as it was read, at the given time since the start of the job.
+fn chunk_to_json(stream: Stream, time: Duration, data: &str) -> serde_json::Value {
+    json!({
+        "stream": stream.name(),
+        "time": time.as_secs_f64(),
+        "data": data,
+    })
+}
+
+/// Decodes both streams as UTF-8, chunk by chunk. A character split between two chunks
+/// of a stream is decoded with the second one, rather than as two replacement characters.
+/// An incomplete character at the very end of a stream is never decoded.
+#[derive(Debug, Default)]
+struct Utf8Decoder {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    fn decode(&mut self, stream: Stream, data: &[u8]) -> String {
+        let incomplete = match stream {
+            Stream::Stdout => &mut self.stdout,
+            Stream::Stderr => &mut self.stderr,
+        };
+
+        let mut bytes = std::mem::take(incomplete);
+        bytes.extend_from_slice(data);
+        *incomplete = bytes.split_off(bytes.len() - incomplete_suffix(&bytes));
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+/// Length of the character at the end of the data, if it is the start of a valid one
+/// which is cut short.
+fn incomplete_suffix(data: &[u8]) -> usize {
+    let tail = &data[data.len().saturating_sub(3)..];
+    let Some(start) = tail.iter().rposition(|&c| c & 0xc0 != 0x80) else {
+        return 0;
+    };
+
+    match std::str::from_utf8(&tail[start..]) {
+        Err(e) if e.error_len().is_none() => tail.len() - start,
+        _ => 0,
+    }
+}
+
+/// Output of both streams of a finished job, interleaved in the order it was read,
+/// in both renderings. Streams are read by different threads, so chunks written
+/// very close to each other may end up in either order.
+#[derive(Clone, Debug, Default)]
+pub struct CombinedOutput {
+    pub text: Blob,
+    pub json: Blob,
+}
+
+/// A job as seen while it is running. The output streams grow as the command writes them.
+#[derive(Debug)]
+pub struct LiveJob {
+    pub id: u64,
+    pub command: Vec<u8>,
+    stdout: Mutex<OutputBuffer>,
+    stderr: Mutex<OutputBuffer>,
+    renderings: Mutex<Renderings>,
+    started: Instant,
+    finished: AtomicBool,
+}
+
+/// Both streams interleaved, kept up to date as output arrives, so that watching them
+/// doesn't render everything again on every read. They become the combined output
+/// of the job once it is finished.
+#[derive(Debug)]
+struct Renderings {
+    text: OutputBuffer,
+    /// Without its closing bracket until it is closed, so that chunks can be added.
+    json: OutputBuffer,
+    closed: bool,
+    decoder: Utf8Decoder,
+}
+
+impl LiveJob {
+    pub fn output(&self, stream: Stream) -> &Mutex<OutputBuffer> {
+        match stream {
+            Stream::Stdout => &self.stdout,
+            Stream::Stderr => &self.stderr,
+        }
+    }
+
+    /// Appends data to one of the output streams, remembering when it was written.
+    pub fn append(&self, stream: Stream, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+
+        // Locks are always taken in the order stdout, stderr, renderings. Renderings are
+        // updated under the lock of the stream, so that they have its chunks in order.
+        let mut output = self.output(stream).lock().unwrap();
+        output.append(data);
+
+        let time = self.started.elapsed();
+        let mut renderings = self.renderings.lock().unwrap();
+        assert!(!renderings.closed, "Output appended to a closed job");
+        renderings.text.append(data);
+        // Past the opening bracket.
+        if renderings.json.len() > 1 {
+            renderings.json.append(b",");
+        }
+        let data = renderings.decoder.decode(stream, data);
+        serde_json::to_writer(&mut renderings.json, &chunk_to_json(stream, time, &data))
+            .expect("Writing to a buffer can't fail");
+    }
+
+    /// Closes the combined output once the streams are read to the end, and returns it.
+    /// The result shares the data of the live files rather than copying it.
+    pub fn close_combined(&self) -> CombinedOutput {
+        let mut renderings = self.renderings.lock().unwrap();
+        if !std::mem::replace(&mut renderings.closed, true) {
+            renderings.json.append(b"]");
+        }
+        CombinedOutput { text: renderings.text.freeze(), json: renderings.json.freeze() }
+    }
+
+    pub fn combined_len(&self, rendering: Rendering) -> usize {
+        let renderings = self.renderings.lock().unwrap();
+        match rendering {
+            Rendering::Text => renderings.text.len(),
+            Rendering::Json => renderings.json.len() + usize::from(!renderings.closed),
+        }
+    }
+
+    /// Reads the given range of the current combined output, clipped to its length.
+    pub fn read_combined(&self, rendering: Rendering, range: Range<usize>) -> io::Result<Vec<u8>> {
+        let renderings = self.renderings.lock().unwrap();
+        match rendering {
+            Rendering::Text => Ok(renderings.text.read_range(range)?.into_owned()),
+            Rendering::Json if renderings.closed => Ok(renderings.json.read_range(range)?.into_owned()),
+            Rendering::Json => {
+                let json = &renderings.json;
+                let mut data = json.read_range(range.clone())?.into_owned();
+                if range.start <= json.len() && range.end > json.len() {
+                    data.push(b']');
+                }
+                Ok(data)
+            }
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Relaxed)
+    }
+
+    pub fn finish(&self) {
+        self.finished.store(true, Ordering::Relaxed);
+    }
+}
+
+/// All the jobs started by the shell, shared with the filesystem so that
+/// their output can be watched while they are still running.
+#[derive(Debug, Default)]
+pub struct JobTable {
+    next_id: AtomicU64,
+    jobs: Mutex<BTreeMap<u64, Arc<LiveJob>>>,
+    /// Where outputs of the jobs go once they get large.
+    spool: Option<Spool>,
+}
+
+impl JobTable {
+    pub fn new(spool: Option<Spool>) -> Self {
+        Self { spool, ..Self::default() }
+    }
+
+    /// Adds a new job to the table and assigns an id to it.
+    pub fn register(&self, command: Vec<u8>) -> Arc<LiveJob> {
+        let mut json = OutputBuffer::new(self.spool.clone());
+        json.append(b"[");
+
+        let job = Arc::new(LiveJob {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            command,
+            stdout: Mutex::new(OutputBuffer::new(self.spool.clone())),
+            stderr: Mutex::new(OutputBuffer::new(self.spool.clone())),
+            renderings: Mutex::new(Renderings {
+                text: OutputBuffer::new(self.spool.clone()),
+                json,
+                closed: false,
+                decoder: Utf8Decoder::default(),
+            }),
+            started: Instant::now(),
+            finished: AtomicBool::new(false),
+        });
+
+        self.jobs.lock().unwrap().insert(job.id, job.clone());
+        job
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<LiveJob>> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    pub fn ids(&self) -> Vec<u64> {
+        self.jobs.lock().unwrap().keys().copied().collect()
+    }
+
+    /// Whether every job in the table is finished.
+    pub fn is_idle(&self) -> bool {
+        self.jobs.lock().unwrap().values().all(|job| job.is_finished())
+    }
+
+    pub fn remove(&self, id: u64) {
+        self.jobs.lock().unwrap().remove(&id);
+    }
+
+    /// Forgets the jobs which are finished. Their results live on elsewhere.
+    pub fn prune_finished(&self) {
+        self.jobs.lock().unwrap().retain(|_, job| !job.is_finished());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prune_finished() {
+        let table = JobTable::default();
+        let first = table.register(b"true".to_vec());
+        let second = table.register(b"false".to_vec());
+        assert_eq!(table.ids(), vec![first.id, second.id]);
+
+        first.finish();
+        assert!(!table.is_idle());
+        table.prune_finished();
+        assert_eq!(table.ids(), vec![second.id]);
+        second.finish();
+        assert!(table.is_idle());
+        assert!(table.get(first.id).is_none());
+    }
+
+    #[test]
+    fn test_combined() {
+        let table = JobTable::default();
+        let job = table.register(b"true".to_vec());
+        job.append(Stream::Stdout, b"one ");
+        job.append(Stream::Stderr, b"two ");
+        job.append(Stream::Stdout, b"three");
+
+        assert_eq!(job.read_combined(Rendering::Text, 0..100).unwrap(), b"one two three");
+        assert_eq!(job.read_combined(Rendering::Text, 4..7).unwrap(), b"two");
+        assert_eq!(job.combined_len(Rendering::Text), 13);
+
+        let len = job.combined_len(Rendering::Json);
+        let rendered = job.read_combined(Rendering::Json, 0..len + 10).unwrap();
+        assert_eq!(rendered.len(), len);
+        assert_eq!(job.read_combined(Rendering::Json, len - 2..len + 10).unwrap(), b"}]");
+        assert!(job.read_combined(Rendering::Json, len..len + 10).unwrap().is_empty());
+
+        let json: serde_json::Value = serde_json::from_slice(&rendered).unwrap();
+        assert_eq!(json[1]["stream"], "stderr");
+        assert_eq!(json[1]["data"], "two ");
+        assert_eq!(json[2]["data"], "three");
+
+        // Closing the output keeps it the same.
+        let combined = job.close_combined();
+        assert_eq!(combined.text.to_vec().unwrap(), b"one two three");
+        assert_eq!(combined.json.to_vec().unwrap(), rendered);
+        assert_eq!(job.close_combined().json.to_vec().unwrap(), rendered);
+        assert_eq!(job.combined_len(Rendering::Json), len);
+        assert_eq!(job.read_combined(Rendering::Json, 0..len + 10).unwrap(), rendered);
+
+        let empty = table.register(b"true".to_vec());
+        assert_eq!(empty.read_combined(Rendering::Json, 0..10).unwrap(), b"[]");
+        assert_eq!(empty.close_combined().json.to_vec().unwrap(), b"[]");
+    }
+
+    #[test]
+    fn test_split_characters() {
+        let table = JobTable::default();
+        let job = table.register(b"true".to_vec());
+        let e = "é".as_bytes();
+        job.append(Stream::Stdout, &[b'a', e[0]]);
+        job.append(Stream::Stderr, &[e[0]]);
+        job.append(Stream::Stdout, &[e[1], b'!']);
+        job.append(Stream::Stderr, b"\xff");
+
+        let rendered = job.read_combined(Rendering::Json, 0..1000).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&rendered).unwrap();
+        assert_eq!(json[0]["data"], "a");
+        assert_eq!(json[1]["data"], "");
+        assert_eq!(json[2]["data"], "é!");
+        assert_eq!(json[3]["data"], "\u{fffd}\u{fffd}");
+
+        assert_eq!(incomplete_suffix(b"abc"), 0);
+        assert_eq!(incomplete_suffix(&"€".as_bytes()[..2]), 2);
+        assert_eq!(incomplete_suffix("€".as_bytes()), 0);
+        assert_eq!(incomplete_suffix(b"\x80\x80\x80"), 0);
+        assert_eq!(incomplete_suffix(b"a\xc3"), 1);
+    }
+}
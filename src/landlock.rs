@@ -0,0 +1,223 @@
+//! Filesystem restrictions for jobs with Landlock, which works without privileges
+//! and without namespaces on Linux 5.13 and later.
+//!
+//! A job may read and execute the system directories, read the project, and write to
+//! its own scratch directory, which is its `TMPDIR`. Everything else is denied. The rules
+//! are added right before exec, after the sandbox is set up if there is one, so that
+//! they refer to what the job is going to see.
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde_json::json;
+use crate::config::LandlockConfig;
+
+// From `linux/landlock.h`.
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+/// Everything up to `MAKE_SYM`, the rights known to the first version.
+const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+
+const ACCESS_READ: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+/// The only rights which make sense for a file rather than a directory.
+const ACCESS_FILE: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE
+    | ACCESS_FS_TRUNCATE | ACCESS_FS_IOCTL_DEV;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// The version of Landlock the kernel supports, zero if it doesn't.
+pub fn abi_version() -> u32 {
+    // SAFETY: with LANDLOCK_CREATE_RULESET_VERSION, no attributes are read and nothing is created.
+    let version = unsafe {
+        libc::syscall(libc::SYS_landlock_create_ruleset, std::ptr::null::<RulesetAttr>(), 0, LANDLOCK_CREATE_RULESET_VERSION)
+    };
+    version.max(0) as u32
+}
+
+/// Rights handled by the given version, everything which is not explicitly allowed is denied.
+fn handled_access(abi: u32) -> u64 {
+    match abi {
+        0 => 0,
+        1 => ACCESS_FS_V1,
+        2 => ACCESS_FS_V1 | ACCESS_FS_REFER,
+        3 | 4 => ACCESS_FS_V1 | ACCESS_FS_REFER | ACCESS_FS_TRUNCATE,
+        _ => ACCESS_FS_V1 | ACCESS_FS_REFER | ACCESS_FS_TRUNCATE | ACCESS_FS_IOCTL_DEV,
+    }
+}
+
+/// Tells scratch directories of the jobs apart.
+static NEXT_SCRATCH: AtomicU64 = AtomicU64::new(0);
+
+/// Rules for a single job, prepared before the fork.
+pub struct Restrictions {
+    abi: u32,
+    read: Vec<PathBuf>,
+    write: Vec<PathBuf>,
+    /// The same paths, ready for system calls.
+    rules: Vec<(CString, u64)>,
+    /// Removed along with the restrictions.
+    scratch: PathBuf,
+    /// The scratch directory and every directory leading to it. They are created again
+    /// by the job if they aren't there, as they may be hidden by the sandbox.
+    scratch_ancestors: Vec<CString>,
+}
+
+impl Restrictions {
+    pub fn new(config: &LandlockConfig, project: Option<&Path>) -> io::Result<Self> {
+        let abi = abi_version();
+        if abi == 0 {
+            return Err(io::Error::other("Landlock is enabled in the configuration, but the kernel doesn't support it"));
+        }
+
+        let id = NEXT_SCRATCH.fetch_add(1, Ordering::Relaxed);
+        let scratch = config.scratch.join(format!("{}-{}", std::process::id(), id));
+        std::fs::create_dir_all(&scratch)?;
+
+        let mut read = config.read.clone();
+        let mut write = config.write.clone();
+        write.push(scratch.clone());
+        if let Some(project) = project {
+            match config.write_project {
+                true => write.push(project.to_path_buf()),
+                false => read.push(project.to_path_buf()),
+            }
+        }
+
+        let c_path = |path: &PathBuf| CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other);
+        let handled = handled_access(abi);
+        let rules = read.iter().map(|path| (path, ACCESS_READ & handled))
+            .chain(write.iter().map(|path| (path, handled)))
+            .map(|(path, access)| Ok((c_path(path)?, access)))
+            .collect::<io::Result<_>>()?;
+
+        let mut scratch_ancestors = scratch.ancestors()
+            .filter(|ancestor| ancestor.parent().is_some())
+            .map(|ancestor| c_path(&ancestor.to_path_buf()))
+            .collect::<io::Result<Vec<_>>>()?;
+        scratch_ancestors.reverse();
+
+        Ok(Self { abi, read, write, rules, scratch, scratch_ancestors })
+    }
+
+    /// A directory only this job can write to.
+    pub fn scratch(&self) -> &Path {
+        &self.scratch
+    }
+
+    /// What the job is allowed to do, for the diagnostics.
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "abi": self.abi,
+            "read": self.read,
+            "write": self.write,
+        })
+    }
+
+    /// Restricts the calling process. Only to be called between fork and exec.
+    pub fn apply(&self) -> io::Result<()> {
+        // Most of the time they exist already, which is fine.
+        for directory in &self.scratch_ancestors {
+            // SAFETY: the path is a valid C string.
+            unsafe { libc::mkdir(directory.as_ptr(), 0o700) };
+        }
+
+        let attr = RulesetAttr { handled_access_fs: handled_access(self.abi) };
+        // SAFETY: the attributes are valid for reads of the size given.
+        let ruleset = unsafe {
+            libc::syscall(libc::SYS_landlock_create_ruleset, &attr, std::mem::size_of::<RulesetAttr>(), 0)
+        };
+        if ruleset < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ruleset = ruleset as libc::c_int;
+
+        let result = self.add_rules(ruleset).and_then(|()| {
+            // Required to restrict itself without privileges.
+            // SAFETY: prctl and landlock_restrict_self have no memory safety requirements.
+            if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+
+        // SAFETY: the ruleset was created above and is not used anymore.
+        unsafe { libc::close(ruleset) };
+        result
+    }
+
+    fn add_rules(&self, ruleset: libc::c_int) -> io::Result<()> {
+        for (path, access) in &self.rules {
+            // SAFETY: the path is a valid C string.
+            let fd = unsafe { libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+            // Not every system has every directory.
+            if fd < 0 {
+                continue;
+            }
+
+            // SAFETY: stat is a plain C struct, all zeroes is a valid value.
+            let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+            // SAFETY: the descriptor is open and the pointer is valid for writes.
+            let is_directory = unsafe { libc::fstat(fd, &mut stat) } == 0
+                && stat.st_mode & libc::S_IFMT == libc::S_IFDIR;
+
+            let rule = PathBeneathAttr {
+                allowed_access: if is_directory { *access } else { access & ACCESS_FILE },
+                parent_fd: fd,
+            };
+            // SAFETY: the rule is valid for reads and laid out the way the kernel expects.
+            let result = unsafe {
+                libc::syscall(libc::SYS_landlock_add_rule, ruleset, LANDLOCK_RULE_PATH_BENEATH, &rule, 0)
+            };
+            let error = io::Error::last_os_error();
+            // SAFETY: the descriptor was opened above and is not used anymore.
+            unsafe { libc::close(fd) };
+
+            if result < 0 {
+                return Err(error);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Restrictions {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.scratch);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handled_access() {
+        assert_eq!(handled_access(0), 0);
+        assert_eq!(handled_access(1) & ACCESS_FS_REFER, 0);
+        assert_eq!(handled_access(3) & ACCESS_FS_TRUNCATE, ACCESS_FS_TRUNCATE);
+        assert_eq!(handled_access(6) & ACCESS_READ, ACCESS_READ);
+    }
+}
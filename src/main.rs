@@ -1,34 +1,57 @@
 mod shell;
 mod fs;
 mod decode;
+mod config;
+mod jobs;
+mod buffer;
+mod notify;
+mod names;
+mod process;
+mod policy;
+mod trust;
+mod sandbox;
+mod landlock;
+mod privileges;
+mod timer;
 
 use std::path::Path;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 use std::thread;
 use fuser::*;
 use fs::ShellEscapeFs;
+use config::Config;
+use jobs::JobTable;
 
 fn main() {
+    let config = Arc::new(Config::from_args());
+    privileges::check(&config.user);
+    let jobs = Arc::new(JobTable::new(config.spool()));
+
     let (command_sender, command_receiver) = mpsc::channel::<shell::Command>();
     let (result_sender, result_receiver) = mpsc::channel::<shell::FinishedCommand>();
 
-    let fs = ShellEscapeFs::new(command_sender, result_receiver);
+    let fs = ShellEscapeFs::new(config.clone(), jobs.clone(), command_sender, result_receiver);
 
     let mount_point = Path::new("/tmp/typst-shell-escape/shell-escape");
 
     if !mount_point.exists() {
-        std::fs::create_dir_all(&mount_point).expect("Failed to create mount point");
+        std::fs::create_dir_all(mount_point).expect("Failed to create mount point");
     } else if !mount_point.is_dir() {
         panic!("Mount point is not a directory");
     }
 
+    let notifier = fs.notifier();
+
     thread::spawn(move || {
-        mount2(fs, &mount_point, &[
+        let mut session = Session::new(fs, mount_point, &[
             MountOption::AutoUnmount,
             MountOption::RO,
             MountOption::AllowOther,
         ]).expect("Failed to mount filesystem");
+
+        notifier.attach(session.notifier());
+        session.run().expect("Failed to run filesystem");
     });
 
-    shell::run(result_sender, command_receiver);
+    shell::run(config, jobs, result_sender, command_receiver);
 }
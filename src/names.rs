@@ -0,0 +1,406 @@
+//! Names of the files looked up in the filesystem.
+//!
+//! Every name in the root and `run` directories has the form
+//!
+//! ```text
+//! name          = [discriminator "_"] action [extension]
+//! discriminator = any bytes, may contain "_" and ".",
+//!                 but the part after its last "_" can't be "sleep" or "exec"
+//! action        = keyword | "sleep_" digits | "exec_" key | hex
+//! key           = any bytes except "_" and ".", at least one
+//! extension     = "." any bytes except "_" and "."
+//! hex           = even number of [0-9a-f], at least two
+//! ```
+//!
+//! The discriminator only exists to defeat caching on the Typst side, so it is ignored.
+//! The extension is ignored as well, except for the files which come in several formats.
+
+use std::fmt;
+use std::time::Duration;
+
+/// Longest sleep a name can ask for. Every sleep holds a request of the kernel
+/// until it is over, so there is no point in sleeping for hours.
+const MAX_SLEEP: Duration = Duration::from_secs(5 * 60);
+
+/// What a name in the root directory asks for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    CurrentDir,
+    RunDir,
+    JobsDir,
+    SessionsDir,
+    Exec,
+    /// Execution which supersedes the previous one with the same key.
+    ExecKeyed(Vec<u8>),
+    Wait,
+    Reset,
+    Prefetch,
+    Diagnostics,
+    Stdout,
+    Stderr,
+    ResultJson,
+    ResultCbor,
+    Output,
+    OutputJson,
+    Log,
+    Random,
+    Nonce,
+    Stats,
+    Sleep(Duration),
+    /// A hex-encoded fragment of the command buffer, still encoded.
+    Append(Vec<u8>),
+}
+
+/// Why a name is not valid.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NameError {
+    Empty,
+    /// The action is neither a keyword nor hex.
+    UnknownAction(Vec<u8>),
+    /// Hex with an odd number of digits, probably cut off or a typo.
+    OddHexLength(Vec<u8>),
+    InvalidSleepDuration(Vec<u8>),
+    EmptyKey,
+    /// A file which comes in several formats was asked for in an unknown one.
+    UnsupportedExtension { action: Vec<u8>, extension: Vec<u8> },
+    InvalidSessionName(Vec<u8>),
+}
+
+impl NameError {
+    /// Error code to reply with. Names which can't mean anything don't exist,
+    /// names which are almost right are invalid.
+    pub fn errno(&self) -> i32 {
+        match self {
+            NameError::Empty | NameError::UnknownAction(..) | NameError::InvalidSessionName(..) => libc::ENOENT,
+            NameError::OddHexLength(..)
+            | NameError::InvalidSleepDuration(..)
+            | NameError::EmptyKey
+            | NameError::UnsupportedExtension { .. } => libc::EINVAL,
+        }
+    }
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NameError::Empty => write!(f, "empty name"),
+            NameError::UnknownAction(action) =>
+                write!(f, "unknown action {:?}", String::from_utf8_lossy(action)),
+            NameError::OddHexLength(hex) =>
+                write!(f, "hex {:?} has an odd number of digits", String::from_utf8_lossy(hex)),
+            NameError::InvalidSleepDuration(millis) =>
+                write!(f, "invalid sleep duration {:?}", String::from_utf8_lossy(millis)),
+            NameError::EmptyKey => write!(f, "empty execution key"),
+            NameError::UnsupportedExtension { action, extension } => write!(
+                f,
+                "{:?} has no {:?} format",
+                String::from_utf8_lossy(action),
+                String::from_utf8_lossy(extension),
+            ),
+            NameError::InvalidSessionName(name) =>
+                write!(f, "invalid session name {:?}", String::from_utf8_lossy(name)),
+        }
+    }
+}
+
+/// A name split into its parts, without interpreting them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Name<'a> {
+    pub discriminator: Option<&'a [u8]>,
+    pub action: &'a [u8],
+    /// Only `sleep` and `exec` take an argument.
+    pub argument: Option<&'a [u8]>,
+    pub extension: Option<&'a [u8]>,
+}
+
+impl<'a> Name<'a> {
+    pub fn split(name: &'a [u8]) -> Self {
+        let (rest, last) = match name.iter().rposition(|&c| c == b'_') {
+            Some(split) => (Some(&name[..split]), &name[split + 1..]),
+            None => (None, name),
+        };
+
+        let (last, extension) = match last.iter().rposition(|&c| c == b'.') {
+            Some(split) => (&last[..split], Some(&last[split + 1..])),
+            None => (last, None),
+        };
+
+        // `sleep_<milliseconds>` and `exec_<key>` are the only actions made of two parts.
+        if let Some(rest) = rest {
+            let (discriminator, previous) = match rest.iter().rposition(|&c| c == b'_') {
+                Some(split) => (Some(&rest[..split]), &rest[split + 1..]),
+                None => (None, rest),
+            };
+
+            if previous == b"sleep" || previous == b"exec" {
+                return Name { discriminator, action: previous, argument: Some(last), extension };
+            }
+        }
+
+        Name { discriminator: rest, action: last, argument: None, extension }
+    }
+}
+
+fn is_hex_digit(c: u8) -> bool {
+    matches!(c, b'a'..=b'f' | b'0'..=b'9')
+}
+
+fn parse_hex(action: &[u8]) -> Result<&[u8], NameError> {
+    if action.is_empty() {
+        return Err(NameError::Empty);
+    }
+
+    if !action.iter().all(|&c| is_hex_digit(c)) {
+        return Err(NameError::UnknownAction(action.to_vec()));
+    }
+
+    if !action.len().is_multiple_of(2) {
+        return Err(NameError::OddHexLength(action.to_vec()));
+    }
+
+    Ok(action)
+}
+
+fn parse_sleep_duration(millis: &[u8]) -> Result<Duration, NameError> {
+    if millis.is_empty() || !millis.iter().all(u8::is_ascii_digit) {
+        return Err(NameError::InvalidSleepDuration(millis.to_vec()));
+    }
+
+    std::str::from_utf8(millis).ok()
+        .and_then(|millis| millis.parse().ok())
+        .map(Duration::from_millis)
+        .filter(|duration| *duration <= MAX_SLEEP)
+        .ok_or_else(|| NameError::InvalidSleepDuration(millis.to_vec()))
+}
+
+/// Parses a name looked up in the root directory.
+pub fn parse_root(name: &[u8]) -> Result<Action, NameError> {
+    if name == b"." {
+        return Ok(Action::CurrentDir);
+    }
+
+    let Name { action, argument, extension, .. } = Name::split(name);
+
+    match (action, argument) {
+        (b"exec", Some(b"")) => return Err(NameError::EmptyKey),
+        (b"exec", Some(key)) => return Ok(Action::ExecKeyed(key.to_vec())),
+        (_, Some(millis)) => return parse_sleep_duration(millis).map(Action::Sleep),
+        (_, None) => {}
+    }
+
+    let unsupported = |extension: &[u8]| NameError::UnsupportedExtension {
+        action: action.to_vec(),
+        extension: extension.to_vec(),
+    };
+
+    Ok(match (action, extension) {
+        (b"run", _) => Action::RunDir,
+        (b"jobs", _) => Action::JobsDir,
+        (b"sessions", _) => Action::SessionsDir,
+        (b"exec", _) => Action::Exec,
+        (b"wait", _) => Action::Wait,
+        (b"reset", _) => Action::Reset,
+        (b"prefetch", _) => Action::Prefetch,
+        (b"diagnostics", _) => Action::Diagnostics,
+        (b"stdout", _) => Action::Stdout,
+        (b"stderr", _) => Action::Stderr,
+        (b"result", Some(b"json")) => Action::ResultJson,
+        (b"result", Some(b"cbor")) => Action::ResultCbor,
+        (b"result", extension) => return Err(unsupported(extension.unwrap_or_default())),
+        (b"output", Some(b"json")) => Action::OutputJson,
+        (b"output", _) => Action::Output,
+        (b"log", _) => Action::Log,
+        (b"random", _) => Action::Random,
+        (b"nonce", _) => Action::Nonce,
+        (b"stats", _) => Action::Stats,
+        (b"sleep", _) => return Err(NameError::InvalidSleepDuration(Vec::new())),
+        (hex, _) => Action::Append(parse_hex(hex)?.to_vec()),
+    })
+}
+
+/// Parses a name looked up in the `run` directory, returning the hex-encoded command.
+pub fn parse_run(name: &[u8]) -> Result<&[u8], NameError> {
+    let Name { action, argument, .. } = Name::split(name);
+    if argument.is_some() {
+        return Err(NameError::UnknownAction(action.to_vec()));
+    }
+
+    parse_hex(action)
+}
+
+/// Parses a name looked up in the `sessions` directory. Session names are
+/// made of letters, digits, `-`, `_` and `.`, and don't start with a dot,
+/// so that hidden files looked up by tools don't start sessions.
+pub fn parse_session(name: &[u8]) -> Result<&[u8], NameError> {
+    let valid = !name.is_empty()
+        && !name.starts_with(b".")
+        && name.iter().all(|&c| c.is_ascii_alphanumeric() || matches!(c, b'-' | b'_' | b'.'));
+
+    if !valid {
+        return Err(NameError::InvalidSessionName(name.to_vec()));
+    }
+
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        assert_eq!(Name::split(b"abc_6c73.txt"), Name {
+            discriminator: Some(b"abc"),
+            action: b"6c73",
+            argument: None,
+            extension: Some(b"txt"),
+        });
+        assert_eq!(Name::split(b"a_b_6c73").discriminator, Some(&b"a_b"[..]));
+        assert_eq!(Name::split(b"a.b_exec"), Name {
+            discriminator: Some(b"a.b"),
+            action: b"exec",
+            argument: None,
+            extension: None,
+        });
+        assert_eq!(Name::split(b"_reset").discriminator, Some(&b""[..]));
+        assert_eq!(Name::split(b"a_b_sleep_1500.txt"), Name {
+            discriminator: Some(b"a_b"),
+            action: b"sleep",
+            argument: Some(b"1500"),
+            extension: Some(b"txt"),
+        });
+        assert_eq!(Name::split(b"sleep_500").discriminator, None);
+        assert_eq!(Name::split(b"x_exec_k3y.txt"), Name {
+            discriminator: Some(b"x"),
+            action: b"exec",
+            argument: Some(b"k3y"),
+            extension: Some(b"txt"),
+        });
+        assert_eq!(Name::split(b"stdout").action, b"stdout");
+    }
+
+    #[test]
+    fn test_parse_root() {
+        assert_eq!(parse_root(b"."), Ok(Action::CurrentDir));
+        assert_eq!(parse_root(b"x_run"), Ok(Action::RunDir));
+        assert_eq!(parse_root(b"Ab9_exec"), Ok(Action::Exec));
+        assert_eq!(parse_root(b"_reset"), Ok(Action::Reset));
+        assert_eq!(parse_root(b"stdout.svg"), Ok(Action::Stdout));
+        assert_eq!(parse_root(b"x_result.json"), Ok(Action::ResultJson));
+        assert_eq!(parse_root(b"x_result.cbor"), Ok(Action::ResultCbor));
+        assert_eq!(parse_root(b"x_output.json"), Ok(Action::OutputJson));
+        assert_eq!(parse_root(b"x_output.txt"), Ok(Action::Output));
+        assert_eq!(parse_root(b"x_stats"), Ok(Action::Stats));
+        assert_eq!(parse_root(b"x_6c73"), Ok(Action::Append(b"6c73".to_vec())));
+
+        assert_eq!(parse_root(b"sleep_500"), Ok(Action::Sleep(Duration::from_millis(500))));
+        assert_eq!(parse_root(b"abc_sleep_0"), Ok(Action::Sleep(Duration::ZERO)));
+        assert_eq!(parse_root(b"a_b_sleep_1500.txt"), Ok(Action::Sleep(Duration::from_millis(1500))));
+        assert_eq!(parse_root(b"x_exec_figure1"), Ok(Action::ExecKeyed(b"figure1".to_vec())));
+        assert_eq!(parse_root(b"exec_k"), Ok(Action::ExecKeyed(b"k".to_vec())));
+
+        assert_eq!(parse_root(b""), Err(NameError::Empty));
+        assert_eq!(parse_root(b"x_"), Err(NameError::Empty));
+        assert_eq!(parse_root(b"x_exce"), Err(NameError::UnknownAction(b"exce".to_vec())));
+        assert_eq!(parse_root(b"x_6C73"), Err(NameError::UnknownAction(b"6C73".to_vec())));
+        assert_eq!(parse_root(b"x_add"), Err(NameError::OddHexLength(b"add".to_vec())));
+        assert_eq!(parse_root(b"abcsleep_500"), Err(NameError::OddHexLength(b"500".to_vec())));
+        assert_eq!(parse_root(b"sleep_"), Err(NameError::InvalidSleepDuration(Vec::new())));
+        assert_eq!(parse_root(b"sleep_1a"), Err(NameError::InvalidSleepDuration(b"1a".to_vec())));
+        assert_eq!(parse_root(b"sleep"), Err(NameError::InvalidSleepDuration(Vec::new())));
+        assert_eq!(parse_root(b"x_exec_"), Err(NameError::EmptyKey));
+        assert_eq!(parse_root(b"x_exec_.txt").unwrap_err().errno(), libc::EINVAL);
+        assert_eq!(parse_root(b"sleep_99999999999999999999"), Err(NameError::InvalidSleepDuration(b"99999999999999999999".to_vec())));
+        assert_eq!(parse_root(b"sleep_300000"), Ok(Action::Sleep(MAX_SLEEP)));
+        assert_eq!(parse_root(b"sleep_300001"), Err(NameError::InvalidSleepDuration(b"300001".to_vec())));
+        assert_eq!(parse_root(b"sleep_300001").unwrap_err().errno(), libc::EINVAL);
+        assert_eq!(parse_root(b"x_result"), Err(NameError::UnsupportedExtension {
+            action: b"result".to_vec(),
+            extension: Vec::new(),
+        }));
+        assert_eq!(parse_root(b"x_result.txt").unwrap_err().errno(), libc::EINVAL);
+        assert_eq!(parse_root(b"x_exce").unwrap_err().errno(), libc::ENOENT);
+    }
+
+    #[test]
+    fn test_parse_run() {
+        assert_eq!(parse_run(b"abc_6c73.txt"), Ok(&b"6c73"[..]));
+        assert_eq!(parse_run(b"6c73"), Ok(&b"6c73"[..]));
+        assert_eq!(parse_run(b"abc_6c7"), Err(NameError::OddHexLength(b"6c7".to_vec())));
+        assert_eq!(parse_run(b"abc_"), Err(NameError::Empty));
+        assert_eq!(parse_run(b"abc_exec"), Err(NameError::UnknownAction(b"exec".to_vec())));
+        assert!(parse_run(b"sleep_10").is_err());
+        assert_eq!(parse_run(b"exec_00"), Err(NameError::UnknownAction(b"exec".to_vec())));
+    }
+
+    #[test]
+    fn test_parse_session() {
+        assert_eq!(parse_session(b"thesis"), Ok(&b"thesis"[..]));
+        assert_eq!(parse_session(b"my-doc_2.typ"), Ok(&b"my-doc_2.typ"[..]));
+        assert!(parse_session(b"").is_err());
+        assert!(parse_session(b".git").is_err());
+        assert!(parse_session(b"a b").is_err());
+        assert_eq!(parse_session(b"\xff").unwrap_err().errno(), libc::ENOENT);
+    }
+
+    /// Deterministic pseudo-random numbers, so that failures can be reproduced.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+            &items[self.next() as usize % items.len()]
+        }
+    }
+
+    #[test]
+    fn test_random_names() {
+        let alphabet = b"_.0123456789abcdefsleepxjsonrcbAZ\0\xff";
+        let mut rng = XorShift(0x2545f4914f6cdd1d);
+
+        for _ in 0..100_000 {
+            let len = rng.next() % 24;
+            let name = (0..len).map(|_| *rng.pick(alphabet)).collect::<Vec<_>>();
+
+            // Never panics, and whatever parses as a command is valid hex.
+            if let Ok(Action::Append(hex)) = parse_root(&name) {
+                assert!(!hex.is_empty() && hex.len() % 2 == 0 && hex.iter().all(|&c| is_hex_digit(c)));
+            }
+            if let Ok(hex) = parse_run(&name) {
+                assert!(!hex.is_empty() && hex.len() % 2 == 0 && hex.iter().all(|&c| is_hex_digit(c)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_random_discriminators() {
+        let discriminators: [&[u8]; 6] = [b"", b"Ab9", b"a_b", b"a.b", b"sleep_x", b"_._"];
+        let actions: [(&[u8], Action); 7] = [
+            (b"exec", Action::Exec),
+            (b"exec_a1", Action::ExecKeyed(b"a1".to_vec())),
+            (b"result.json", Action::ResultJson),
+            (b"output.svg", Action::Output),
+            (b"sleep_25", Action::Sleep(Duration::from_millis(25))),
+            (b"00ff", Action::Append(b"00ff".to_vec())),
+            (b"wait.txt", Action::Wait),
+        ];
+        let mut rng = XorShift(0x9e3779b97f4a7c15);
+
+        for _ in 0..10_000 {
+            let discriminator = rng.pick(&discriminators);
+            let (action, expected) = rng.pick(&actions);
+
+            let mut name = discriminator.to_vec();
+            name.push(b'_');
+            name.extend_from_slice(action);
+
+            assert_eq!(parse_root(&name).as_ref(), Ok(expected), "{:?}", String::from_utf8_lossy(&name));
+        }
+    }
+}
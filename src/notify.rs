@@ -0,0 +1,83 @@
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::mpsc;
+use std::sync::OnceLock;
+use std::thread;
+
+/// Something the kernel has cached which is no longer true.
+#[derive(Debug, PartialEq, Eq)]
+enum Notification {
+    /// Attributes and content of a file.
+    Inode(u64),
+    /// A name in a directory.
+    Entry(u64, Vec<u8>),
+}
+
+/// Tells the kernel to drop what it has cached about files which changed.
+///
+/// Notifications are sent from a separate thread: the kernel may have to wait for
+/// a request to finish before it can apply one, and requests are served by
+/// the same thread as the one which wants to notify.
+#[derive(Debug, Default)]
+pub struct Notifier {
+    notifications: OnceLock<mpsc::Sender<Notification>>,
+}
+
+impl Notifier {
+    /// Starts sending notifications through the notifier of the mounted session.
+    /// Notifications are dropped until then.
+    pub fn attach(&self, notifier: fuser::Notifier) {
+        let (sender, receiver) = mpsc::channel::<Notification>();
+        thread::spawn(move || {
+            for notification in receiver {
+                // The kernel answers with ENOENT if it has nothing cached, which is fine.
+                let _ = match notification {
+                    // Zero length means up to the end of the file.
+                    Notification::Inode(inode) => notifier.inval_inode(inode, 0, 0),
+                    Notification::Entry(parent, name) => notifier.inval_entry(parent, OsStr::from_bytes(&name)),
+                };
+            }
+        });
+
+        self.notifications.set(sender).expect("Notifier is already attached");
+    }
+
+    /// Invalidates the attributes and cached content of a file.
+    pub fn inval_inode(&self, inode: u64) {
+        self.send(Notification::Inode(inode));
+    }
+
+    /// Invalidates the directory entry with the given name, so that the next access looks it up again.
+    pub fn inval_entry(&self, parent: u64, name: &[u8]) {
+        self.send(Notification::Entry(parent, name.to_vec()));
+    }
+
+    fn send(&self, notification: Notification) {
+        if let Some(notifications) = self.notifications.get() {
+            let _ = notifications.send(notification);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notifications() {
+        let notifier = Notifier::default();
+
+        // Nothing is sent before the notifier is attached.
+        notifier.inval_inode(1);
+
+        let (sender, receiver) = mpsc::channel();
+        notifier.notifications.set(sender).unwrap();
+
+        notifier.inval_inode(300);
+        assert_eq!(receiver.recv().unwrap(), Notification::Inode(300));
+        notifier.inval_entry(3, b"12");
+        assert_eq!(receiver.recv().unwrap(), Notification::Entry(3, b"12".to_vec()));
+
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,378 @@
+//! Rules deciding which commands may run at all.
+//!
+//! A command line is split into simple commands, for example `curl x | jq .` into
+//! `curl x` and `jq .`. Every simple command is judged by the first rule which matches it,
+//! or by the default if none does, and the command line runs only if all of them are allowed.
+//! The splitting only understands quotes, escapes and the usual operators, which is enough
+//! to find the programs, but not to see what command substitutions run. So a command line
+//! with substitutions is only allowed when the default is to allow.
+//!
+//! Reserved words such as `if` or `!` and the usual wrappers such as `env` or `nice` are
+//! looked through, so `env rm` is judged as `rm`. Programs which run their arguments
+//! in other ways, like `sh -c` or `xargs`, are not, so only a policy which denies
+//! by default keeps out what it doesn't list.
+
+use std::collections::VecDeque;
+use std::fmt;
+use regex::bytes::Regex;
+use serde::{Deserialize, Deserializer};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// A regular expression from the configuration.
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    anywhere: Regex,
+    whole: Regex,
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        let compile = |pattern: &str| Regex::new(pattern).map_err(serde::de::Error::custom);
+        Ok(Pattern {
+            anywhere: compile(&pattern)?,
+            whole: compile(&format!("^(?:{})$", pattern))?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub action: Verdict,
+    /// Name of the program, either as written or without its directory.
+    #[serde(default)]
+    pub program: Option<String>,
+    /// Searched for anywhere in the simple command being judged, its words joined by spaces.
+    /// Not in the whole line, which would let an allowed command vouch for the others.
+    #[serde(default)]
+    pub command: Option<Pattern>,
+    /// Patterns for the arguments, in order. Each has to match the whole argument,
+    /// further arguments are not checked.
+    #[serde(default)]
+    pub args: Option<Vec<Pattern>>,
+}
+
+impl Rule {
+    fn matches(&self, words: &[Vec<u8>]) -> bool {
+        let Some((program, args)) = words.split_first() else {
+            return false;
+        };
+
+        let program_matches = self.program.as_ref().is_none_or(|name| {
+            let name = name.as_bytes();
+            program == name || program.rsplit(|&c| c == b'/').next() == Some(name)
+        });
+
+        let args_match = self.args.as_ref().is_none_or(|patterns| {
+            patterns.len() <= args.len()
+                && patterns.iter().zip(args).all(|(pattern, arg)| pattern.whole.is_match(arg))
+        });
+
+        let command_matches = self.command.as_ref().is_none_or(|pattern| pattern.anywhere.is_match(&words.join(&b' ')));
+
+        program_matches && args_match && command_matches
+    }
+}
+
+/// Why a command was denied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Denial {
+    pub program: Vec<u8>,
+    /// Index of the rule which denied the command, `None` if it was the default.
+    pub rule: Option<usize>,
+}
+
+impl fmt::Display for Denial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let program = String::from_utf8_lossy(&self.program);
+        match self.rule {
+            Some(rule) => write!(f, "{:?} is denied by rule {}", program, rule),
+            None if self.program.is_empty() => write!(f, "command substitutions are denied by default"),
+            None => write!(f, "{:?} is denied by default", program),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub default: Verdict,
+    pub rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn check(&self, line: &[u8]) -> Result<(), Denial> {
+        let (commands, substitutions) = split_commands(line);
+
+        for words in &commands {
+            let rule = self.rules.iter().position(|rule| rule.matches(words));
+            let verdict = rule.map_or(self.default, |rule| self.rules[rule].action);
+            if verdict == Verdict::Deny {
+                return Err(Denial { program: words[0].clone(), rule });
+            }
+        }
+
+        if substitutions && self.default == Verdict::Deny {
+            return Err(Denial { program: Vec::new(), rule: None });
+        }
+
+        Ok(())
+    }
+}
+
+/// Splits a command line into simple commands made of words, with quotes and escapes removed,
+/// variable assignments and redirections dropped. Also tells whether it has command substitutions.
+fn split_commands(line: &[u8]) -> (Vec<Vec<Vec<u8>>>, bool) {
+    let mut commands = vec![];
+    let mut words: Vec<Vec<u8>> = vec![];
+    let mut word: Option<Vec<u8>> = None;
+    let mut substitutions = false;
+
+    let mut bytes = line.iter().copied().peekable();
+    while let Some(c) = bytes.next() {
+        match c {
+            b'\'' => {
+                let word = word.get_or_insert_with(Vec::new);
+                word.extend(bytes.by_ref().take_while(|&c| c != b'\''));
+            }
+            b'"' => {
+                let word = word.get_or_insert_with(Vec::new);
+                while let Some(c) = bytes.next() {
+                    match c {
+                        b'"' => break,
+                        b'\\' => word.extend(bytes.next()),
+                        b'`' => { substitutions = true; word.push(c) }
+                        b'$' if bytes.peek() == Some(&b'(') => { substitutions = true; word.push(c) }
+                        _ => word.push(c),
+                    }
+                }
+            }
+            b'\\' => word.get_or_insert_with(Vec::new).extend(bytes.next()),
+            b'`' => { substitutions = true; word.get_or_insert_with(Vec::new).push(c) }
+            b'$' if bytes.peek() == Some(&b'(') => { substitutions = true; word.get_or_insert_with(Vec::new).push(c) }
+            b'<' | b'>' if bytes.peek() == Some(&b'(') => { substitutions = true; word.get_or_insert_with(Vec::new).push(c) }
+            // `2>&1` and `&>` are redirections, not background jobs.
+            b'&' if word.as_ref().is_some_and(|word| word.ends_with(b">") || word.ends_with(b"<"))
+                || bytes.peek() == Some(&b'>') => word.get_or_insert_with(Vec::new).push(c),
+            b' ' | b'\t' => words.extend(word.take()),
+            b';' | b'&' | b'|' | b'\n' | b'(' | b')' => {
+                words.extend(word.take());
+                commands.extend(simple_command(std::mem::take(&mut words)));
+            }
+            _ => word.get_or_insert_with(Vec::new).push(c),
+        }
+    }
+
+    words.extend(word);
+    commands.extend(simple_command(words));
+    (commands, substitutions)
+}
+
+/// Words which start a part of a compound command rather than a program.
+const RESERVED_WORDS: &[&[u8]] = &[
+    b"!", b"{", b"}", b"if", b"then", b"elif", b"else", b"fi", b"while", b"until", b"do", b"done", b"esac",
+];
+
+/// Words which start a part of a compound command made of data, not of commands.
+const DATA_WORDS: &[&[u8]] = &[b"for", b"select", b"case"];
+
+/// A builtin or program which runs its arguments as a command.
+struct Wrapper {
+    name: &'static [u8],
+    /// Options which take the next word as their argument.
+    options: &'static [&'static [u8]],
+    /// How many arguments come before the command.
+    positional: usize,
+}
+
+const WRAPPERS: &[Wrapper] = &[
+    Wrapper { name: b"builtin", options: &[], positional: 0 },
+    Wrapper { name: b"command", options: &[], positional: 0 },
+    Wrapper { name: b"coproc", options: &[], positional: 0 },
+    Wrapper { name: b"env", options: &[b"-u", b"--unset", b"-C", b"--chdir"], positional: 0 },
+    Wrapper { name: b"exec", options: &[b"-a"], positional: 0 },
+    Wrapper { name: b"nice", options: &[b"-n", b"--adjustment"], positional: 0 },
+    Wrapper { name: b"nohup", options: &[], positional: 0 },
+    Wrapper { name: b"setsid", options: &[], positional: 0 },
+    Wrapper { name: b"stdbuf", options: &[b"-i", b"-o", b"-e", b"--input", b"--output", b"--error"], positional: 0 },
+    Wrapper { name: b"time", options: &[b"-f", b"--format", b"-o", b"--output"], positional: 0 },
+    Wrapper { name: b"timeout", options: &[b"-s", b"--signal", b"-k", b"--kill-after"], positional: 1 },
+];
+
+fn is_assignment(word: &[u8]) -> bool {
+    let Some(equals) = word.iter().position(|&c| c == b'=') else {
+        return false;
+    };
+    let name = &word[..equals];
+    !name.is_empty()
+        && !name[0].is_ascii_digit()
+        && name.iter().all(|&c| c.is_ascii_alphanumeric() || c == b'_')
+}
+
+/// Drops what doesn't belong to the program and its arguments from the words of a simple command.
+fn simple_command(words: Vec<Vec<u8>>) -> Option<Vec<Vec<u8>>> {
+    // The operator of a redirection, if the word is one.
+    let redirection = |word: &[u8]| {
+        let digits = word.iter().take_while(|c| c.is_ascii_digit()).count();
+        let operator = &word[digits..];
+        let is_redirection = operator.starts_with(b"<") || operator.starts_with(b">") || operator.starts_with(b"&>");
+        is_redirection.then(|| operator.iter().all(|&c| matches!(c, b'<' | b'>' | b'&')))
+    };
+
+    let mut result = VecDeque::new();
+    let mut words = words.into_iter();
+    while let Some(word) = words.next() {
+        if let Some(alone) = redirection(&word) {
+            // The target is a separate word if the operator is alone.
+            if alone {
+                words.next();
+            }
+            continue;
+        }
+        result.push_back(word);
+    }
+
+    unwrap_command(&mut result)?;
+    (!result.is_empty()).then_some(result.into())
+}
+
+/// Drops leading assignments, reserved words and wrappers from a simple command,
+/// until the words start with the program which actually runs.
+/// Returns `None` if the command doesn't run anything.
+fn unwrap_command(words: &mut VecDeque<Vec<u8>>) -> Option<()> {
+    while let Some(first) = words.front() {
+        let name = first.rsplit(|&c| c == b'/').next().unwrap_or(first);
+
+        if is_assignment(first) || RESERVED_WORDS.contains(&first.as_slice()) {
+            words.pop_front();
+        } else if DATA_WORDS.contains(&first.as_slice()) {
+            return None;
+        } else if first == b"function" {
+            // The name of the function, its body follows.
+            words.drain(..words.len().min(2));
+        } else if let Some(wrapper) = WRAPPERS.iter().find(|wrapper| wrapper.name == name) {
+            let env = name == b"env";
+            words.pop_front();
+
+            while let Some(word) = words.front() {
+                if word == b"--" {
+                    words.pop_front();
+                    break;
+                } else if env && (word == b"-S" || word == b"--split-string") {
+                    // The next word is split into the command and its first arguments.
+                    words.pop_front();
+                    let split = words.pop_front().unwrap_or_default();
+                    for word in split.split(|c| c.is_ascii_whitespace()).filter(|word| !word.is_empty()).rev() {
+                        words.push_front(word.to_vec());
+                    }
+                } else if wrapper.options.contains(&word.as_slice()) {
+                    words.drain(..words.len().min(2));
+                } else if word.starts_with(b"-") && word.len() > 1 {
+                    words.pop_front();
+                } else {
+                    break;
+                }
+            }
+
+            words.drain(..words.len().min(wrapper.positional));
+        } else {
+            break;
+        }
+    }
+
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(line: &str) -> Vec<Vec<String>> {
+        split_commands(line.as_bytes()).0.into_iter()
+            .map(|words| words.into_iter().map(|word| String::from_utf8(word).unwrap()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_split_commands() {
+        assert_eq!(words("ls -la /"), [["ls", "-la", "/"]]);
+        assert_eq!(words("curl 'a b' | jq \".x\" && rm\\ f"), vec![vec!["curl", "a b"], vec!["jq", ".x"], vec!["rm f"]]);
+        assert_eq!(words("A=1 B=2 python -c x 2>&1 >out; (cd dir)"), vec![vec!["python", "-c", "x"], vec!["cd", "dir"]]);
+        assert_eq!(words("cat < in > out &"), [["cat"]]);
+        assert_eq!(words("echo a=b"), [["echo", "a=b"]]);
+        assert!(words("").is_empty());
+
+        // Reserved words and wrappers are looked through.
+        assert_eq!(words("command rm -rf /"), [["rm", "-rf", "/"]]);
+        assert_eq!(words("env rm x; /usr/bin/env -i -u HOME -- A=1 rm y"), [["rm", "x"], ["rm", "y"]]);
+        assert_eq!(words("env -S 'rm -rf' /"), [["rm", "-rf", "/"]]);
+        assert_eq!(words("exec -a name rm; nice rm; nice -n 5 rm; nohup nice -n5 rm"), [["rm"], ["rm"], ["rm"], ["rm"]]);
+        assert_eq!(words("timeout -s KILL 5 rm x; time -p stdbuf -oL rm"), vec![vec!["rm", "x"], vec!["rm"]]);
+        assert_eq!(words("! rm; { rm; }; ! A=1 command -p rm"), [["rm"], ["rm"], ["rm"]]);
+        assert_eq!(words("if x; then rm y; elif z; then :; else rm; fi"), vec![vec!["x"], vec!["rm", "y"], vec!["z"], vec![":"], vec!["rm"]]);
+        assert_eq!(words("while true; do rm; done"), [["true"], ["rm"]]);
+        assert_eq!(words("for f in *.svg; do rm $f; done"), [["rm", "$f"]]);
+        assert_eq!(words("case x in a) rm;; esac"), [["rm"]]);
+        assert_eq!(words("function f { rm; }; g() { rm; }"), [["rm"], ["g"], ["rm"]]);
+        assert_eq!(words("echo if env"), [["echo", "if", "env"]]);
+        assert!(words("env A=1").is_empty());
+
+        assert!(!split_commands(b"echo '$(x)'").1);
+        assert!(split_commands(b"echo \"$(x)\"").1);
+        assert!(split_commands(b"echo `x`").1);
+        assert!(split_commands(b"diff <(a) b").1);
+    }
+
+    #[test]
+    fn test_check() {
+        let policy: Policy = serde_json::from_str(r#"{
+            "default": "deny",
+            "rules": [
+                {"action": "deny", "command": "--upload-file"},
+                {"action": "allow", "program": "curl", "args": ["-sS"]},
+                {"action": "allow", "program": "echo"},
+                {"action": "allow", "program": "git", "args": ["status|log"]}
+            ]
+        }"#).unwrap();
+
+        assert!(policy.check(b"echo hi").is_ok());
+        assert!(policy.check(b"/bin/echo hi | echo").is_ok());
+        assert!(policy.check(b"curl -sS https://example.com").is_ok());
+        assert!(policy.check(b"git log --oneline").is_ok());
+
+        assert_eq!(policy.check(b"curl -sS --upload-file x y"), Err(Denial { program: b"curl".to_vec(), rule: Some(0) }));
+        assert_eq!(policy.check(b"curl https://example.com"), Err(Denial { program: b"curl".to_vec(), rule: None }));
+        assert_eq!(policy.check(b"echo hi; rm -rf /").unwrap_err().program, b"rm");
+        assert!(policy.check(b"git status-not").is_err());
+        assert!(policy.check(b"git").is_err());
+        assert_eq!(policy.check(b"echo $(echo hi)").unwrap_err().to_string(), "command substitutions are denied by default");
+
+        assert!(Policy::default().check(b"echo $(anything)").is_ok());
+
+        let policy: Policy = serde_json::from_str(r#"{"rules": [{"action": "deny", "program": "rm"}]}"#).unwrap();
+        let bypasses: &[&[u8]] = &[
+            b"command rm x", b"env rm x", b"exec rm x", b"nice rm x", b"! rm x",
+            b"{ rm x; }", b"if true; then rm x; fi", b"/usr/bin/env rm x",
+        ];
+        for line in bypasses {
+            assert_eq!(policy.check(line), Err(Denial { program: b"rm".to_vec(), rule: Some(0) }), "{}", String::from_utf8_lossy(line));
+        }
+        assert!(policy.check(b"echo rm").is_ok());
+        assert!(serde_json::from_str::<Policy>(r#"{"rules": [{"action": "deny", "command": "("}]}"#).is_err());
+
+        // An allowed command doesn't vouch for the others on the line.
+        let policy: Policy = serde_json::from_str(r#"{"default": "deny", "rules": [{"action": "allow", "command": "^git "}]}"#).unwrap();
+        assert!(policy.check(b"git status").is_ok());
+        assert!(policy.check(b"A=1 git status | git log").is_ok());
+        assert_eq!(policy.check(b"git x; rm -rf ~"), Err(Denial { program: b"rm".to_vec(), rule: None }));
+        assert!(policy.check(b"rm -rf ~ # git x").is_err());
+    }
+}
@@ -0,0 +1,91 @@
+//! The user commands run as.
+//!
+//! FUSE only lets other users see the filesystem with `user_allow_other`, and it is
+//! tempting to run the daemon as root instead, which would run every command as root
+//! too. So commands can be given a user and group of their own, and the daemon refuses
+//! to start if they would run as root, unless that is explicitly allowed.
+
+use std::io;
+use crate::config::UserConfig;
+
+/// The user and group commands switch to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+impl Credentials {
+    /// `None` if commands run as the user of the daemon.
+    pub fn new(config: &UserConfig) -> Option<Self> {
+        Some(Self { uid: config.uid?, gid: config.gid? })
+    }
+
+    /// The effective user and group of the daemon itself.
+    pub fn current() -> Self {
+        // SAFETY: geteuid and getegid have no memory safety requirements.
+        unsafe { Self { uid: libc::geteuid(), gid: libc::getegid() } }
+    }
+
+    /// Switches the calling process to them, leaving no supplementary groups behind.
+    /// Only to be called between fork and exec.
+    ///
+    /// Nothing changes if they are the ones of the daemon already: dropping the
+    /// supplementary groups takes privileges a daemon not running as root doesn't have.
+    pub fn switch(&self) -> io::Result<()> {
+        if *self == Self::current() {
+            return Ok(());
+        }
+
+        // The group goes first, changing it is no longer allowed after the user.
+        // SAFETY: an empty list of groups is read through no pointer, and setgid and setuid
+        // have no memory safety requirements.
+        if unsafe { libc::setgroups(0, std::ptr::null()) } < 0
+            || unsafe { libc::setgid(self.gid) } < 0
+            || unsafe { libc::setuid(self.uid) } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Panics if the configuration is incomplete, or if commands would run as root without it being allowed.
+pub fn check(config: &UserConfig) {
+    if config.uid.is_some() != config.gid.is_some() {
+        panic!("user.uid and user.gid have to be given together");
+    }
+
+    let uid = Credentials::new(config).unwrap_or_else(Credentials::current).uid;
+    if uid == 0 && !config.allow_root {
+        panic!("Refusing to run commands as root. Set user.uid and user.gid to run them as someone else, or user.allow_root to run them as root anyway");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let config = |json: &str| serde_json::from_str::<UserConfig>(json).unwrap();
+        let panics = |json: &str| {
+            let config = config(json);
+            std::panic::catch_unwind(|| check(&config)).is_err()
+        };
+
+        assert!(!panics(r#"{"uid": 1000, "gid": 1000}"#));
+        assert!(!panics(r#"{"uid": 0, "gid": 0, "allow_root": true}"#));
+        assert!(panics(r#"{"uid": 0, "gid": 0}"#));
+        assert!(panics(r#"{"uid": 1000}"#));
+        assert_eq!(panics("{}"), Credentials::current().uid == 0);
+        assert_eq!(Credentials::new(&config(r#"{"uid": 1, "gid": 2}"#)), Some(Credentials { uid: 1, gid: 2 }));
+        assert_eq!(Credentials::new(&config("{}")), None);
+    }
+
+    #[test]
+    fn test_switch_to_current() {
+        // Whoever runs the tests, switching to themselves is allowed.
+        Credentials::current().switch().unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+//! Information about the processes which use the filesystem.
+
+use std::ffi::OsStr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// The thread group, that is the process, the given thread belongs to.
+/// FUSE reports the thread which made a request, and compilers read files from many threads.
+pub fn thread_group(tid: u32) -> Option<u32> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", tid)).ok()?;
+    status.lines()
+        .find_map(|line| line.strip_prefix("Tgid:"))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// The directory of the project the given process works on, which is where its commands are run.
+/// That is the `--root` of a Typst compiler if it is given, the directory of the input file
+/// if there is one, and the working directory of the process otherwise.
+pub fn project_directory(pid: u32) -> Option<PathBuf> {
+    let cwd = std::fs::read_link(format!("/proc/{}/cwd", pid)).ok()?;
+    let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+    let args = cmdline.split(|&c| c == 0).map(OsStr::from_bytes).collect::<Vec<_>>();
+    Some(project_directory_from(&cwd, &args))
+}
+
+fn project_directory_from(cwd: &Path, args: &[&OsStr]) -> PathBuf {
+    let mut root = None;
+    let mut input = None;
+
+    // The program name is skipped, the rest is parsed like Typst does, as far as it matters.
+    let mut args = args.iter().skip(1).map(|arg| arg.as_bytes());
+    while let Some(arg) = args.next() {
+        if arg == b"--root" {
+            root = args.next();
+        } else if let Some(value) = arg.strip_prefix(b"--root=") {
+            root = Some(value);
+        } else if input.is_none() && !arg.starts_with(b"-") && arg.ends_with(b".typ") {
+            input = Some(arg);
+        }
+    }
+
+    match (root, input) {
+        (Some(root), _) => cwd.join(OsStr::from_bytes(root)),
+        (None, Some(input)) => {
+            let input = cwd.join(OsStr::from_bytes(input));
+            input.parent().map_or(input.clone(), Path::to_path_buf)
+        }
+        (None, None) => cwd.to_path_buf(),
+    }
+}
+
+/// Notices when a process exits, without keeping it around like a child would be.
+pub struct ProcessWatch {
+    pidfd: Option<OwnedFd>,
+    exited: bool,
+}
+
+impl ProcessWatch {
+    pub fn new(pid: u32) -> Self {
+        // SAFETY: pidfd_open has no memory safety requirements.
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+        if fd >= 0 {
+            // SAFETY: the descriptor was just opened, and nothing else owns it.
+            return Self { pidfd: Some(unsafe { OwnedFd::from_raw_fd(fd as i32) }), exited: false };
+        }
+
+        let error = std::io::Error::last_os_error();
+        if error.raw_os_error() == Some(libc::ESRCH) {
+            return Self { pidfd: None, exited: true };
+        }
+
+        // Old kernels don't have pidfds, the process is then assumed to live forever.
+        eprintln!("Can't watch process {}: {}", pid, error);
+        Self { pidfd: None, exited: false }
+    }
+
+    pub fn has_exited(&self) -> bool {
+        let Some(pidfd) = &self.pidfd else {
+            return self.exited;
+        };
+
+        // A pidfd becomes readable once the process exits.
+        let mut poll = libc::pollfd { fd: pidfd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        // SAFETY: the pointer is valid for a single pollfd, and the descriptor is open.
+        unsafe { libc::poll(&mut poll, 1, 0) > 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thread_group() {
+        let pid = std::process::id();
+        assert_eq!(thread_group(pid), Some(pid));
+
+        // SAFETY: gettid has no memory safety requirements.
+        let tid = std::thread::spawn(|| unsafe { libc::gettid() } as u32).join().unwrap();
+        assert_ne!(tid, pid);
+        // The thread is gone by now, but its id is not worth anything anyway.
+        assert!(thread_group(tid).is_none_or(|group| group == pid));
+
+        // SAFETY: as above.
+        let tid = unsafe { libc::gettid() } as u32;
+        assert_eq!(thread_group(tid), Some(pid));
+    }
+
+    #[test]
+    fn test_process_watch() {
+        assert!(!ProcessWatch::new(std::process::id()).has_exited());
+
+        let mut child = std::process::Command::new("sleep").arg("10").spawn().unwrap();
+        let watch = ProcessWatch::new(child.id());
+        assert!(!watch.has_exited());
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(watch.has_exited());
+        assert!(ProcessWatch::new(child.id()).has_exited());
+    }
+
+    #[test]
+    fn test_project_directory() {
+        let cwd = Path::new("/home/user");
+        let directory = |args: &[&str]| {
+            let args = args.iter().map(OsStr::new).collect::<Vec<_>>();
+            project_directory_from(cwd, &args)
+        };
+
+        assert_eq!(directory(&["typst", "watch", "thesis/main.typ"]), Path::new("/home/user/thesis"));
+        assert_eq!(directory(&["typst", "compile", "--root", "..", "main.typ"]), Path::new("/home/user/.."));
+        assert_eq!(directory(&["typst", "c", "main.typ", "--root=/srv"]), Path::new("/srv"));
+        assert_eq!(directory(&["typst-lsp"]), cwd);
+        assert_eq!(directory(&[]), cwd);
+
+        let pid = std::process::id();
+        assert_eq!(project_directory(pid), std::env::current_dir().ok());
+    }
+}
@@ -0,0 +1,354 @@
+//! Isolation of jobs with unprivileged user namespaces.
+//!
+//! The shell of a sandboxed job gets its own network namespace without any interfaces
+//! but loopback, its own mount namespace where `/tmp` and the hidden directories are
+//! empty and the project is read-only, and its own pid namespace, where it is pid 1
+//! and can't see or signal anything outside.
+//!
+//! A pid namespace only applies to the children of the process which creates it, so the
+//! shell is cloned straight into the new namespaces by the daemon, and is its child like
+//! any other job. Everything between the clone and exec happens in a copy of the daemon
+//! where only system calls are allowed, so all paths are prepared beforehand.
+
+use std::convert::Infallible;
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::File;
+use std::io::{self, PipeReader, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use crate::config::SandboxConfig;
+use crate::privileges::Credentials;
+
+/// Stack of the cloned process until it execs. It only makes system calls, but
+/// preparing the job may still go through a few frames of Rust code.
+const STACK_SIZE: usize = 256 * 1024;
+
+/// Everything a job needs to enter the sandbox, prepared before the clone.
+pub struct Sandbox {
+    network: bool,
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+    /// Only root can map a group without giving up supplementary groups first.
+    deny_setgroups: bool,
+    hidden: Vec<CString>,
+    project: Option<Project>,
+    /// The working directory, entered once the mounts are in place.
+    directory: CString,
+}
+
+struct Project {
+    /// Reserves a descriptor number for the project, which is opened again in the new
+    /// mount namespace before anything is mounted, so that it can be found even if it is
+    /// under a hidden directory. A bind mount can't come from the namespace outside.
+    handle: File,
+    handle_path: CString,
+    /// The path itself, and every directory leading to it, to be created if they are hidden.
+    path: CString,
+    ancestors: Vec<CString>,
+    /// Flags of the original mount, an unprivileged remount has to keep them.
+    flags: libc::c_ulong,
+}
+
+/// The shell of a job, running in the sandbox.
+pub struct SandboxedShell {
+    pub pid: u32,
+    pub stdout: PipeReader,
+    pub stderr: PipeReader,
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other)
+}
+
+/// Turns a system call result into an error if it failed.
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl Sandbox {
+    /// The credentials are the ones the job has inside the sandbox.
+    pub fn new(config: &SandboxConfig, project: Option<&Path>, credentials: Credentials) -> io::Result<Self> {
+        let Credentials { uid, gid } = credentials;
+
+        let hidden = config.hide.iter()
+            .filter(|path| path.is_dir())
+            .map(|path| c_path(path))
+            .collect::<io::Result<_>>()?;
+
+        let project = project.map(|path| -> io::Result<Project> {
+            let handle = File::open(path)?;
+            // SAFETY: statvfs is a plain C struct, all zeroes is a valid value.
+            let mut stat = unsafe { std::mem::zeroed::<libc::statvfs>() };
+            // SAFETY: the descriptor is open and the pointer is valid for writes.
+            check(unsafe { libc::fstatvfs(handle.as_raw_fd(), &mut stat) })?;
+
+            let flags = [
+                (libc::ST_NOSUID, libc::MS_NOSUID),
+                (libc::ST_NODEV, libc::MS_NODEV),
+                (libc::ST_NOEXEC, libc::MS_NOEXEC),
+                (libc::ST_NOATIME, libc::MS_NOATIME),
+                (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+                (libc::ST_RELATIME, libc::MS_RELATIME),
+            ].into_iter()
+                .filter(|&(st, _)| stat.f_flag & st != 0)
+                .fold(0, |flags, (_, ms)| flags | ms);
+
+            let mut ancestors = path.ancestors()
+                .filter(|ancestor| ancestor.parent().is_some())
+                .map(c_path)
+                .collect::<io::Result<Vec<_>>>()?;
+            ancestors.reverse();
+
+            Ok(Project {
+                handle_path: c_path(&PathBuf::from(format!("/proc/self/fd/{}", handle.as_raw_fd())))?,
+                handle,
+                path: c_path(path)?,
+                ancestors,
+                flags,
+            })
+        }).transpose()?;
+
+        let directory = match project.as_ref() {
+            Some(project) => project.path.clone(),
+            None => c_path(&std::env::current_dir()?)?,
+        };
+
+        Ok(Self {
+            network: config.network,
+            uid_map: format!("{} {} 1", uid, uid).into_bytes(),
+            gid_map: format!("{} {} 1", gid, gid).into_bytes(),
+            // SAFETY: geteuid has no memory safety requirements.
+            deny_setgroups: unsafe { libc::geteuid() } != 0,
+            hidden,
+            project,
+            directory,
+        })
+    }
+
+    /// Starts `sh -c <command>` in the sandbox, in a session of its own, with stdout and stderr
+    /// piped, and the given variables added to the environment. `prepare` runs in the shell process once it is in the sandbox, right
+    /// before exec, and has the same restrictions as `pre_exec`.
+    pub fn spawn(
+        &self,
+        command: &OsStr,
+        variables: &[(&OsStr, &OsStr)],
+        mut prepare: impl FnMut() -> io::Result<()>,
+    ) -> io::Result<SandboxedShell> {
+        let program = find_program("sh")?;
+        let arguments = [program.clone(), c"-c".into(), CString::new(command.as_bytes()).map_err(io::Error::other)?];
+        let environment = std::env::vars_os()
+            .filter(|(name, _)| variables.iter().all(|(variable, _)| name != variable))
+            .chain(variables.iter().map(|&(name, value)| (name.into(), value.into())))
+            .map(|(name, value)| CString::new([name.as_bytes(), b"=", value.as_bytes()].concat()).map_err(io::Error::other))
+            .collect::<io::Result<Vec<_>>>()?;
+        let pointers = |strings: &[CString]| strings.iter()
+            .map(|string| string.as_ptr())
+            .chain([std::ptr::null()])
+            .collect::<Vec<_>>();
+        let (argv, envp) = (pointers(&arguments), pointers(&environment));
+
+        let (stdout, stdout_writer) = io::pipe()?;
+        let (stderr, stderr_writer) = io::pipe()?;
+        // Closed on exec, which is how the shell reports that it has started. Otherwise it
+        // gets the error number.
+        let (mut errors, errors_writer) = io::pipe()?;
+        // Holds the shell back until its ids are mapped.
+        let (ready, mut ready_writer) = io::pipe()?;
+
+        let mut child = Child {
+            sandbox: self,
+            prepare: &mut prepare,
+            program: &program,
+            argv: argv.as_ptr(),
+            envp: envp.as_ptr(),
+            stdout: stdout_writer.as_raw_fd(),
+            stderr: stderr_writer.as_raw_fd(),
+            errors: errors_writer.as_raw_fd(),
+            ready: ready.as_raw_fd(),
+            ready_writer: ready_writer.as_raw_fd(),
+        };
+
+        let mut flags = libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWPID | libc::SIGCHLD;
+        if !self.network {
+            flags |= libc::CLONE_NEWNET;
+        }
+
+        let mut stack = vec![0u8; STACK_SIZE];
+        // SAFETY: the stack grows down from its end, aligned the way every architecture wants
+        // it. Without CLONE_VM the child gets a copy of the memory of the daemon, where the
+        // stack and everything the argument points to stay valid until it execs or exits.
+        let pid = unsafe {
+            let top = stack.as_mut_ptr().add(STACK_SIZE);
+            let top = top.sub(top as usize % 16);
+            libc::clone(start, top.cast(), flags, (&raw mut child).cast())
+        };
+        check(pid)?;
+        drop((stack, stdout_writer, stderr_writer, errors_writer, ready));
+
+        if let Err(e) = self.map_ids(pid).and_then(|()| ready_writer.write_all(b"!")) {
+            // SAFETY: kill has no memory safety requirements.
+            unsafe { libc::kill(pid, libc::SIGKILL) };
+            reap(pid);
+            return Err(e);
+        }
+        drop(ready_writer);
+
+        let mut errno = [0; 4];
+        match errors.read(&mut errno)? {
+            0 => Ok(SandboxedShell { pid: pid as u32, stdout, stderr }),
+            _ => {
+                reap(pid);
+                Err(io::Error::from_raw_os_error(i32::from_ne_bytes(errno)))
+            }
+        }
+    }
+
+    /// Keeps the same ids inside, so that the files of the user still belong to them.
+    fn map_ids(&self, pid: libc::pid_t) -> io::Result<()> {
+        std::fs::write(format!("/proc/{}/uid_map", pid), &self.uid_map)?;
+        if self.deny_setgroups {
+            std::fs::write(format!("/proc/{}/setgroups", pid), b"deny")?;
+        }
+        std::fs::write(format!("/proc/{}/gid_map", pid), &self.gid_map)
+    }
+
+    /// Sets up the namespaces of the calling process, only to be called in the cloned shell.
+    fn enter(&self) -> io::Result<()> {
+        // Otherwise mounts would propagate back to the rest of the system.
+        self.mount(None, c"/", None, libc::MS_REC | libc::MS_PRIVATE)?;
+
+        if let Some(project) = &self.project {
+            // SAFETY: the path is a valid C string.
+            let fd = unsafe { libc::open(project.path.as_ptr(), libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC) };
+            check(fd)?;
+            // SAFETY: both descriptors are open, and the handle is not used as a File in this process.
+            check(unsafe { libc::dup3(fd, project.handle.as_raw_fd(), libc::O_CLOEXEC) })?;
+            // SAFETY: the descriptor was opened above and is not used anymore.
+            unsafe { libc::close(fd) };
+        }
+
+        self.mount(Some(c"tmpfs"), c"/tmp", Some(c"tmpfs"), libc::MS_NOSUID | libc::MS_NODEV)?;
+        for hidden in &self.hidden {
+            self.mount(Some(c"tmpfs"), hidden, Some(c"tmpfs"), libc::MS_NOSUID | libc::MS_NODEV)?;
+        }
+
+        if let Some(project) = &self.project {
+            for ancestor in &project.ancestors {
+                // Most of them exist already, which is fine.
+                // SAFETY: the path is a valid C string.
+                unsafe { libc::mkdir(ancestor.as_ptr(), 0o755) };
+            }
+
+            self.mount(Some(&project.handle_path), &project.path, None, libc::MS_BIND | libc::MS_REC)?;
+            let flags = libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | project.flags;
+            self.mount(None, &project.path, None, flags)?;
+            // The handle is closed on exec anyway, but the command has no business seeing it.
+            // SAFETY: same as above, the handle is not used as a File in this process.
+            unsafe { libc::close(project.handle.as_raw_fd()) };
+        }
+
+        // SAFETY: the paths are valid C strings.
+        if unsafe { libc::chdir(self.directory.as_ptr()) } < 0 {
+            check(unsafe { libc::chdir(c"/".as_ptr()) })?;
+        }
+
+        // The first process in the new pid namespace, so this /proc only shows the job.
+        self.mount(Some(c"proc"), c"/proc", Some(c"proc"), libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC)
+    }
+
+    fn mount(&self, source: Option<&CStr>, target: &CStr, kind: Option<&CStr>, flags: libc::c_ulong) -> io::Result<()> {
+        let pointer = |string: Option<&CStr>| string.map_or(std::ptr::null(), |string| string.as_ptr());
+        // SAFETY: the strings are valid C strings or null, which mount accepts for these arguments.
+        check(unsafe { libc::mount(pointer(source), target.as_ptr(), pointer(kind), flags, std::ptr::null()) })
+    }
+}
+
+/// What the cloned shell needs until it execs, all of it prepared by the daemon.
+struct Child<'a> {
+    sandbox: &'a Sandbox,
+    prepare: &'a mut dyn FnMut() -> io::Result<()>,
+    program: &'a CStr,
+    argv: *const *const libc::c_char,
+    envp: *const *const libc::c_char,
+    stdout: RawFd,
+    stderr: RawFd,
+    errors: RawFd,
+    ready: RawFd,
+    ready_writer: RawFd,
+}
+
+impl Child<'_> {
+    fn exec(&mut self) -> io::Result<Infallible> {
+        // SAFETY: these only take descriptors and plain values. The descriptors belong to the
+        // daemon, which has its own copies of them.
+        unsafe {
+            libc::close(self.ready_writer);
+            // Its own session, so that everything it spawns can be killed along with it,
+            // and so that reading the terminal it inherits doesn't stop it.
+            check(libc::setsid())?;
+            check(libc::dup2(self.stdout, libc::STDOUT_FILENO))?;
+            check(libc::dup2(self.stderr, libc::STDERR_FILENO))?;
+        }
+
+        // The same as std does for the processes it spawns.
+        // SAFETY: the signal set is initialized by sigemptyset before it is used.
+        unsafe {
+            let mut signals = std::mem::zeroed::<libc::sigset_t>();
+            libc::sigemptyset(&mut signals);
+            check(libc::sigprocmask(libc::SIG_SETMASK, &signals, std::ptr::null_mut()))?;
+            libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+        }
+
+        // Nothing can be done in the namespaces before the ids are mapped. The daemon
+        // kills the shell if it can't map them.
+        let mut byte = 0u8;
+        // SAFETY: the pointer is valid for a write of one byte.
+        while unsafe { libc::read(self.ready, (&raw mut byte).cast(), 1) } < 0 {
+            if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        self.sandbox.enter()?;
+        (self.prepare)()?;
+
+        // SAFETY: the program and both arrays are valid and null-terminated.
+        unsafe { libc::execve(self.program.as_ptr(), self.argv, self.envp) };
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Entry point of the cloned shell. Never returns, it either execs or reports why it couldn't.
+extern "C" fn start(argument: *mut libc::c_void) -> libc::c_int {
+    // SAFETY: it is the Child passed to clone, in the copy of the memory of the daemon.
+    let child = unsafe { &mut *argument.cast::<Child>() };
+    let Err(e) = child.exec();
+    let errno = e.raw_os_error().unwrap_or(libc::EINVAL).to_ne_bytes();
+    // SAFETY: the pointer is valid for reads of the whole error number.
+    unsafe {
+        libc::write(child.errors, errno.as_ptr().cast(), errno.len());
+        libc::_exit(127)
+    }
+}
+
+/// Waits for a shell which failed to start, so that it doesn't stay around as a zombie.
+fn reap(pid: libc::pid_t) {
+    // SAFETY: a null status pointer is allowed.
+    while unsafe { libc::waitpid(pid, std::ptr::null_mut(), 0) } < 0
+        && io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) {}
+}
+
+/// Finds a program in `PATH` the way `execvp` would, which can't be used after the clone.
+fn find_program(name: &str) -> io::Result<CString> {
+    let path = std::env::var_os("PATH").unwrap_or_else(|| "/usr/bin:/bin".into());
+    let program = std::env::split_paths(&path)
+        .map(|directory| directory.join(name))
+        .find(|program| program.metadata().is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} is not in PATH", name)))?;
+    c_path(&program)
+}
@@ -1,31 +1,144 @@
-use std::ffi::OsString;
-use std::io::Read;
-use std::os::unix::ffi::OsStringExt;
-use std::sync::mpsc;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
 use std::thread;
+use std::time::{Duration, SystemTime};
 use serde_json::json;
-use wait_timeout::ChildExt;
+use sha2::{Digest, Sha256};
+use crate::buffer::{Blob, ReadRange};
+use crate::config::{Config, OverflowAction};
+use crate::decode::hex_encode;
+use crate::jobs::{CombinedOutput, JobTable, LiveJob, Stream};
+use crate::landlock::Restrictions;
+use crate::privileges::Credentials;
+use crate::process::ProcessWatch;
+use crate::sandbox::Sandbox;
+
+/// How often a running command is checked for completion or termination requests.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A command line along with the directory to run it in,
+/// the working directory of the daemon if there is none.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Job {
+    pub command: Vec<u8>,
+    pub directory: Option<PathBuf>,
+    /// The process which asked for the command. The command is cancelled once it exits.
+    pub owner: Option<u32>,
+    /// Starting a job cancels the previous one with the same key, if it is still running.
+    pub key: Option<Vec<u8>>,
+}
+
+impl Job {
+    /// The same job, as it would have been prefetched. Prefetched jobs are claimed by whoever
+    /// executes the same command in the same directory first, so they belong to nobody.
+    fn prefetched(&self) -> Job {
+        Job { owner: None, key: None, ..self.clone() }
+    }
+}
+
+impl From<Vec<u8>> for Job {
+    fn from(command: Vec<u8>) -> Self {
+        Self { command, directory: None, owner: None, key: None }
+    }
+}
+
+/// Receives the result of a job, on the thread which ran it.
+pub type Report = Box<dyn FnOnce(FinishedCommand) + Send>;
 
 pub enum Command {
-    Execute(Vec<u8>),
+    Execute(Job),
+    /// Executes the job like [Command::Execute], but hands its result to the given function
+    /// instead of sending it with the results of the shell.
+    Run(Job, Report),
+    /// Replaces the lookahead queue with the given jobs and starts them
+    /// in the background. Their results are kept until an [Command::Execute]
+    /// with the same job claims them.
+    Prefetch(Vec<Job>),
+    /// Reports the job as denied by the policy instead of running it.
+    Deny(Job, String),
     TerminateAll,
+    /// Terminates every job, the prefetched ones as well, and stops the shell.
+    /// Nothing is reported, the session is gone by then.
+    Shutdown,
+}
+
+/// Resources consumed by a finished command, as reported by `wait4`.
+pub struct ResourceUsage {
+    user_time: Duration,
+    system_time: Duration,
+    /// Peak resident set size, in bytes.
+    max_rss: u64,
+}
+
+impl From<libc::rusage> for ResourceUsage {
+    fn from(usage: libc::rusage) -> Self {
+        let duration = |time: libc::timeval| {
+            Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+        };
+
+        Self {
+            user_time: duration(usage.ru_utime),
+            system_time: duration(usage.ru_stime),
+            // Linux reports it in kilobytes.
+            max_rss: usage.ru_maxrss as u64 * 1024,
+        }
+    }
+}
+
+/// An output stream of a command, possibly cut short by the size limit.
+pub struct CapturedOutput {
+    /// Shared with every file serving this output, so it is never copied again.
+    pub data: Blob,
+    /// Number of bytes the command has written, including the discarded ones.
+    pub total_size: u64,
+}
+
+impl CapturedOutput {
+    pub fn truncated(&self) -> bool {
+        self.total_size > self.data.len() as u64
+    }
 }
 
 pub enum ExecutionResult {
     Ran {
         error_code: i32,
-        stdout: Vec<u8>,
-        stderr: Vec<u8>,
+        stdout: CapturedOutput,
+        stderr: CapturedOutput,
+        /// Both streams in the order they were written.
+        combined: CombinedOutput,
+        usage: ResourceUsage,
     },
     FailedToSpawn(std::io::Error),
     FailedToWait(std::io::Error),
+    /// The command was not started, because nobody needs it anymore.
+    Cancelled(&'static str),
+    Denied(String),
 }
 
 pub struct FinishedExecution {
+    /// Id of the job in the [JobTable].
+    job_id: u64,
     command: Vec<u8>,
+    directory: Option<PathBuf>,
     pub(crate) result: ExecutionResult,
+    /// Pid of the shell running the command, if it was spawned.
+    pid: Option<u32>,
+    /// What the command was allowed to access, if it was restricted with Landlock.
+    landlock: Option<serde_json::Value>,
+    started_at: SystemTime,
+    finished_at: SystemTime,
 }
 
+// Results are sent around rarely, boxing them is not worth it.
+#[allow(clippy::large_enum_variant)]
 pub enum FinishedCommand {
     Execution(FinishedExecution),
     Termination,
@@ -34,9 +147,18 @@ pub enum FinishedCommand {
 impl FinishedExecution {
     pub fn summarize_into_json(&self) -> serde_json::Value {
         let result = match &self.result {
-            ExecutionResult::Ran { error_code, .. } => json!({
+            ExecutionResult::Ran { error_code, stdout, stderr, usage, .. } => json!({
                 "ran": true,
                 "error_code": error_code,
+                "user_time": usage.user_time.as_secs_f64(),
+                "system_time": usage.system_time.as_secs_f64(),
+                "max_rss": usage.max_rss,
+                "stdout_size": stdout.total_size,
+                "stdout_truncated": stdout.truncated(),
+                "stdout_sha256": sha256_hex(&stdout.data).ok(),
+                "stderr_size": stderr.total_size,
+                "stderr_truncated": stderr.truncated(),
+                "stderr_sha256": sha256_hex(&stderr.data).ok(),
             }),
             ExecutionResult::FailedToSpawn(e) => json!({
                 "ran": false,
@@ -48,103 +170,1046 @@ impl FinishedExecution {
                 "error": "Failed to wait",
                 "message": e.to_string(),
             }),
+            ExecutionResult::Cancelled(reason) => json!({
+                "ran": false,
+                "error": "Cancelled",
+                "message": reason,
+            }),
+            ExecutionResult::Denied(reason) => json!({
+                "ran": false,
+                "error": "Denied by policy",
+                "message": reason,
+            }),
         };
 
         json!({
+            "job": self.job_id,
             "command": String::from_utf8_lossy(&self.command).to_string(),
+            "pid": self.pid,
+            "directory": self.directory.as_ref().map(|directory| directory.to_string_lossy()),
+            "landlock": self.landlock,
+            "timings": self.timings_into_json(),
             "result": result,
         })
     }
+
+    fn timings_into_json(&self) -> serde_json::Value {
+        let since_epoch = |time: SystemTime| time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_secs_f64();
+
+        let wall_time = self.finished_at
+            .duration_since(self.started_at)
+            .unwrap_or(Duration::ZERO);
+
+        json!({
+            "started_at": since_epoch(self.started_at),
+            "finished_at": since_epoch(self.finished_at),
+            "wall_time": wall_time.as_secs_f64(),
+        })
+    }
+
+    /// Output streams of the command. Empty if the command did not run.
+    pub fn outputs(&self) -> (Blob, Blob) {
+        match &self.result {
+            ExecutionResult::Ran { stdout, stderr, .. } => (stdout.data.clone(), stderr.data.clone()),
+            _ => (Blob::default(), Blob::default()),
+        }
+    }
+
+    /// Output of both streams in the order it was written, if the command ran.
+    pub fn combined_output(&self) -> Option<&CombinedOutput> {
+        match &self.result {
+            ExecutionResult::Ran { combined, .. } => Some(combined),
+            _ => None,
+        }
+    }
+
+    /// Writes everything known about the execution as a single JSON document.
+    /// Output streams are base64-encoded, since they don't have to be valid UTF-8.
+    /// They are streamed chunk by chunk rather than held in memory as a whole.
+    pub fn write_bundle_json(&self, mut writer: impl Write) -> io::Result<()> {
+        let (stdout, stderr) = self.outputs();
+
+        // The summary is always a non-empty object, so the streams can be
+        // added in place of its closing brace.
+        let mut summary = serde_json::to_vec(&self.summarize_into_json())?;
+        summary.pop();
+        writer.write_all(&summary)?;
+
+        for (name, data) in [("stdout", stdout), ("stderr", stderr)] {
+            write!(writer, ",\"{}\":\"", name)?;
+            let mut encoder = base64::write::EncoderWriter::new(&mut writer, &base64::engine::general_purpose::STANDARD);
+            data.for_each_chunk(|chunk| encoder.write_all(chunk))?;
+            encoder.finish()?.write_all(b"\"")?;
+        }
+
+        writer.write_all(b"}")
+    }
+
+    /// Same as [FinishedExecution::write_bundle_json], but in CBOR,
+    /// where output streams are kept as raw byte strings.
+    pub fn write_bundle_cbor(&self, mut writer: impl Write) -> io::Result<()> {
+        let (stdout, stderr) = self.outputs();
+
+        let ciborium::value::Value::Map(entries) = ciborium::value::Value::serialized(&self.summarize_into_json())
+            .expect("JSON is always representable in CBOR") else {
+            unreachable!("JSON object is serialized into a CBOR map");
+        };
+
+        writer.write_all(&cbor_head(CBOR_MAP, entries.len() as u64 + 2))?;
+        for (key, value) in &entries {
+            write_cbor(key, &mut writer)?;
+            write_cbor(value, &mut writer)?;
+        }
+
+        for (name, data) in [("stdout", stdout), ("stderr", stderr)] {
+            write_cbor(&name.into(), &mut writer)?;
+            writer.write_all(&cbor_head(CBOR_BYTES, data.len() as u64))?;
+            data.for_each_chunk(|chunk| writer.write_all(chunk))?;
+        }
+
+        Ok(())
+    }
 }
 
-pub struct Terminate;
+const CBOR_BYTES: u8 = 2;
+const CBOR_MAP: u8 = 5;
+
+/// Head of a CBOR data item of the given major type and length, in its shortest form.
+/// Lets large byte strings be written without holding them in memory.
+fn cbor_head(major: u8, length: u64) -> Vec<u8> {
+    let major = major << 5;
+    match length {
+        0..24 => vec![major | length as u8],
+        24..0x100 => vec![major | 24, length as u8],
+        0x100..0x10000 => [&[major | 25][..], &(length as u16).to_be_bytes()].concat(),
+        0x10000..0x1_0000_0000 => [&[major | 26][..], &(length as u32).to_be_bytes()].concat(),
+        _ => [&[major | 27][..], &length.to_be_bytes()].concat(),
+    }
+}
+
+fn write_cbor(value: &ciborium::value::Value, writer: impl Write) -> io::Result<()> {
+    ciborium::ser::into_writer(value, writer).map_err(|e| match e {
+        ciborium::ser::Error::Io(e) => e,
+        ciborium::ser::Error::Value(message) => io::Error::other(message),
+    })
+}
+
+/// Why a job is terminated.
+pub enum Terminate {
+    /// A newer job with the same key took its place.
+    Superseded,
+    /// Everything is terminated at once, or the job is not needed anymore.
+    Cancelled,
+}
+
+fn sha256_hex(data: &Blob) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    data.for_each_chunk(|chunk| {
+        hasher.update(chunk);
+        Ok(())
+    })?;
+    Ok(String::from_utf8(hex_encode(&hasher.finalize())).expect("Hex is valid UTF-8"))
+}
+
+/// Waits for a child process with `wait4`, which, unlike `waitpid`, also reports
+/// resources used by the process.
+fn wait4(pid: u32) -> std::io::Result<(libc::c_int, libc::rusage)> {
+    let mut status = 0;
+    // SAFETY: rusage is a plain C struct, all zeroes is a valid value.
+    let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+
+    // SAFETY: both pointers are valid for writes for the duration of the call.
+    match unsafe { libc::wait4(pid as libc::pid_t, &mut status, 0, &mut usage) } {
+        -1 => Err(std::io::Error::last_os_error()),
+        _ => Ok((status, usage)),
+    }
+}
+
+/// Whether the process has exited, without reaping it.
+fn has_exited(pid: u32) -> std::io::Result<bool> {
+    // SAFETY: siginfo_t is a plain C struct, all zeroes is a valid value.
+    let mut info = unsafe { std::mem::zeroed::<libc::siginfo_t>() };
+    let options = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+
+    // SAFETY: the pointer is valid for writes for the duration of the call.
+    if unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, options) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: waitid has filled in the pid, it is still zero if the process is running.
+    Ok(unsafe { info.si_pid() } != 0)
+}
+
+/// Waits for at most the given time for something to read from the descriptor, or for it to be closed.
+fn readable(fd: RawFd, timeout: Duration) -> bool {
+    let mut poll = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    // SAFETY: the pointer is valid for the one descriptor given.
+    unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as libc::c_int) > 0 }
+}
+
+/// Number of bytes waiting to be read from a pipe.
+fn available(fd: RawFd) -> usize {
+    let mut available: libc::c_int = 0;
+    // SAFETY: FIONREAD writes a single int through the pointer.
+    match unsafe { libc::ioctl(fd, libc::FIONREAD, &mut available) } {
+        -1 => 0,
+        _ => available as usize,
+    }
+}
+
+/// Reads a stream to the end into the job, keeping at most `limit` bytes of it.
+/// Sets `overflowed` as soon as anything has to be discarded. Once `exited` is set,
+/// only what is already in the pipe is read: a process the job left running in the
+/// background could keep the pipe open long after the job is over.
+fn capture(
+    mut reader: File,
+    job: &LiveJob,
+    stream: Stream,
+    limit: Option<u64>,
+    overflowed: &AtomicBool,
+    exited: &AtomicBool,
+) -> CapturedOutput {
+    let mut total_size = 0;
+    let mut kept = 0;
+    let mut chunk = [0; 8192];
+    // What is left to read once the shell has exited.
+    let mut remaining = None;
+
+    loop {
+        if remaining.is_none() && exited.load(Ordering::Acquire) {
+            remaining = Some(available(reader.as_raw_fd()));
+        }
+
+        let size = match remaining {
+            Some(0) => break,
+            Some(remaining) => remaining.min(chunk.len()),
+            None if !readable(reader.as_raw_fd(), POLL_INTERVAL) => continue,
+            None => chunk.len(),
+        };
+
+        let read = match reader.read(&mut chunk[..size]) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+
+        total_size += read as u64;
+        if let Some(remaining) = &mut remaining {
+            *remaining -= read;
+        }
+
+        let room = limit.map_or(read, |limit| limit.saturating_sub(kept).min(read as u64) as usize);
+        job.append(stream, &chunk[..room]);
+        kept += room as u64;
+
+        if room < read {
+            overflowed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // The live buffer is kept for anyone still watching the job.
+    let data = job.output(stream).lock().unwrap().freeze();
+    CapturedOutput { data, total_size }
+}
+
+/// Kills every process in the process group led by the given process.
+fn kill_group(pid: u32) {
+    // SAFETY: kill has no memory safety requirements.
+    unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+}
+
+/// Exit code of a process given its wait status.
+/// Processes killed by a signal are reported the same way shells do, as 128 + signal.
+fn exit_code(status: libc::c_int) -> i32 {
+    if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        libc::WEXITSTATUS(status)
+    }
+}
+
+/// A command that was started speculatively, but nobody asked for it yet.
+struct PrefetchedJob {
+    worker: thread::JoinHandle<()>,
+    termination_sender: mpsc::Sender<Terminate>,
+    result_receiver: mpsc::Receiver<FinishedCommand>,
+}
+
+impl PrefetchedJob {
+    fn start(job: Job, config: Arc<Config>, jobs: Arc<JobTable>) -> Self {
+        let (termination_sender, termination_receiver) = mpsc::channel::<Terminate>();
+        let (result_sender, result_receiver) = mpsc::channel::<FinishedCommand>();
+
+        let worker = thread::spawn(move || {
+            let result = run_one(job, &config, &jobs, termination_receiver);
+            // Nobody is interested in the result if the job was dropped from the queue.
+            let _ = result_sender.send(result);
+        });
+
+        Self { worker, termination_sender, result_receiver }
+    }
+
+    fn terminate(self) {
+        let _ = self.termination_sender.send(Terminate::Cancelled);
+        self.worker.join().expect("Failed to join worker");
+    }
+}
 
 /// Starts the main loop of the shell.
-pub fn run(result_sender: mpsc::Sender<FinishedCommand>, command_receiver: mpsc::Receiver<Command>) {
+pub fn run(
+    config: Arc<Config>,
+    jobs: Arc<JobTable>,
+    result_sender: mpsc::Sender<FinishedCommand>,
+    command_receiver: mpsc::Receiver<Command>,
+) {
     let mut workers = vec![];
     let mut termination_senders = vec![];
+    let mut prefetched: HashMap<Job, PrefetchedJob> = HashMap::new();
+    // Termination senders of the latest job with each key.
+    let mut latest: HashMap<Vec<u8>, mpsc::Sender<Terminate>> = HashMap::new();
 
     loop {
-        let command = command_receiver.recv().expect("Failed to receive command");
+        let command = match command_receiver.recv().expect("Failed to receive command") {
+            Command::Execute(job) => {
+                let result_sender = result_sender.clone();
+                // Nobody waits for the result if the session was dropped in the meantime.
+                Command::Run(job, Box::new(move |result| { let _ = result_sender.send(result); }))
+            }
+            command => command,
+        };
+        let key = match &command {
+            Command::Run(job, _) => job.key.clone(),
+            _ => None,
+        };
 
         match command {
-            Command::Execute(command) => {
+            Command::Execute(..) => unreachable!("Executions are turned into runs above"),
+            Command::Run(job, report) if prefetched.contains_key(&job.prefetched()) => {
+                let job = prefetched.remove(&job.prefetched()).expect("Job is known to be prefetched");
+
+                // The job is already running (or even finished), so we only
+                // have to forward its result when it arrives.
+                let result_receiver = job.result_receiver;
+                workers.push(thread::spawn(move || {
+                    report(result_receiver.recv().expect("Failed to receive result"));
+                }));
+                workers.push(job.worker);
+
+                termination_senders.push(job.termination_sender);
+            }
+            Command::Run(job, report) => {
                 let (termination_sender, termination_receiver) = mpsc::channel::<Terminate>();
-                let result_sender = result_sender.clone();
+                let config = config.clone();
+                let jobs = jobs.clone();
 
                 workers.push(thread::spawn(move || {
-                    let result = run_one(command, termination_receiver);
-                    result_sender.send(result).expect("Failed to send result");
+                    report(run_one(job, &config, &jobs, termination_receiver));
                 }));
 
                 termination_senders.push(termination_sender);
             }
+            Command::Prefetch(queue) => {
+                let queue = queue.iter().map(Job::prefetched).collect::<Vec<_>>();
+                let stale = prefetched.keys()
+                    .filter(|job| !queue.contains(job))
+                    .cloned()
+                    .collect::<Vec<_>>();
+
+                for job in stale {
+                    prefetched.remove(&job).expect("Job is known to be prefetched").terminate();
+                }
+
+                for job in queue {
+                    prefetched.entry(job.clone())
+                        .or_insert_with(|| PrefetchedJob::start(job, config.clone(), jobs.clone()));
+                }
+            }
+            Command::Deny(job, reason) => {
+                result_sender.send(deny(job, reason, &jobs)).expect("Failed to send result");
+            }
+            // Prefetched jobs are deliberately left alone: the document resets
+            // before every command, and the whole point is to keep them running.
             Command::TerminateAll => {
                 while let Some(termination_sender) = termination_senders.pop() {
                     // If this fails, it means that the command is already executed and
                     // there is no need to terminate it.
-                    let _ = termination_sender.send(Terminate);
+                    let _ = termination_sender.send(Terminate::Cancelled);
                 }
 
                 while let Some(worker) = workers.pop() {
                     worker.join().expect("Failed to join worker");
                 }
+                latest.clear();
+
+                jobs.prune_finished();
 
                 result_sender.send(FinishedCommand::Termination)
                     .expect("Failed to send termination");
             }
+            Command::Shutdown => {
+                for (_, job) in prefetched.drain() {
+                    job.terminate();
+                }
+                for termination_sender in termination_senders.drain(..) {
+                    let _ = termination_sender.send(Terminate::Cancelled);
+                }
+                for worker in workers.drain(..) {
+                    worker.join().expect("Failed to join worker");
+                }
+                return;
+            }
+        }
+
+        // The new job supersedes the previous one with the same key. The previous one
+        // still reports a result, so every execution is matched by exactly one wait.
+        if let Some(key) = key {
+            let sender = termination_senders.last().expect("Execution adds a termination sender").clone();
+            if let Some(previous) = latest.insert(key, sender) {
+                let _ = previous.send(Terminate::Superseded);
+            }
         }
     }
 }
 
-/// Runs a single command. Should be ran in a separate thread.
-pub fn run_one(command: Vec<u8>, termination_receiver: mpsc::Receiver<Terminate>) -> FinishedCommand {
+/// Makes up the result of a job which is not allowed to run.
+fn deny(Job { command, directory, .. }: Job, reason: String, jobs: &JobTable) -> FinishedCommand {
+    let job = jobs.register(command.clone());
+    job.finish();
+    jobs.remove(job.id);
+
     let mut command = command.to_vec();
     command.push(b'\n');
 
-    let mut child = match std::process::Command::new("sh")
-        .arg("-c")
-        .arg(OsString::from_vec(command.clone()))
+    let now = SystemTime::now();
+    FinishedCommand::Execution(FinishedExecution {
+        job_id: job.id,
+        command,
+        directory,
+        result: ExecutionResult::Denied(reason),
+        pid: None,
+        landlock: None,
+        started_at: now,
+        finished_at: now,
+    })
+}
+
+/// The shell of a job which has just started.
+struct Spawned {
+    pid: u32,
+    stdout: File,
+    stderr: File,
+}
+
+/// Starts the shell of a job, in the sandbox if it is enabled. The user is switched and the
+/// restrictions applied right before exec, in the sandbox already, so that the paths of the
+/// restrictions are the ones the job sees.
+fn spawn(
+    command: &[u8],
+    directory: Option<&Path>,
+    restrictions: Option<Arc<Restrictions>>,
+    config: &Config,
+) -> std::io::Result<Spawned> {
+    let credentials = Credentials::new(&config.user);
+    if let (Some(restrictions), Some(credentials)) = (&restrictions, credentials) {
+        std::os::unix::fs::chown(restrictions.scratch(), Some(credentials.uid), Some(credentials.gid))?;
+    }
+
+    let tmpdir = restrictions.as_ref().map(|restrictions| restrictions.scratch().to_path_buf());
+    let prepare = move || {
+        if let Some(credentials) = credentials {
+            credentials.switch()?;
+        }
+        if let Some(restrictions) = &restrictions {
+            restrictions.apply()?;
+        }
+        Ok(())
+    };
+
+    if config.sandbox.enabled {
+        let sandbox = Sandbox::new(&config.sandbox, directory, credentials.unwrap_or_else(Credentials::current))?;
+        let variables = tmpdir.iter()
+            .map(|tmpdir| (OsStr::new("TMPDIR"), tmpdir.as_os_str()))
+            .collect::<Vec<_>>();
+        let shell = sandbox.spawn(OsStr::from_bytes(command), &variables, prepare)?;
+        return Ok(Spawned {
+            pid: shell.pid,
+            stdout: OwnedFd::from(shell.stdout).into(),
+            stderr: OwnedFd::from(shell.stderr).into(),
+        });
+    }
+
+    let mut shell = std::process::Command::new("sh");
+    shell.arg("-c")
+        .arg(OsStr::from_bytes(command))
         .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn() {
-        Ok(child) => child,
-        Err(e) => return FinishedCommand::Execution(FinishedExecution {
+        .stderr(std::process::Stdio::piped());
+
+    if let Some(directory) = directory {
+        shell.current_dir(directory);
+    }
+    if let Some(tmpdir) = &tmpdir {
+        shell.env("TMPDIR", tmpdir);
+    }
+    // SAFETY: starting a session, switching users and applying the restrictions only make system calls.
+    unsafe {
+        shell.pre_exec(move || {
+            // A session of its own, so that everything it spawns can be killed along with it, and
+            // so that the terminal it inherits as stdin is not its controlling terminal: reading
+            // it would stop a process group which is not in the foreground.
+            if libc::setsid() < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            prepare()
+        })
+    };
+
+    let mut child = shell.spawn()?;
+    Ok(Spawned {
+        pid: child.id(),
+        stdout: OwnedFd::from(child.stdout.take().expect("Stdout is piped")).into(),
+        stderr: OwnedFd::from(child.stderr.take().expect("Stderr is piped")).into(),
+    })
+}
+
+/// Runs a single command. Should be ran in a separate thread.
+pub fn run_one(
+    Job { command, directory, owner, key }: Job,
+    config: &Config,
+    jobs: &JobTable,
+    termination_receiver: mpsc::Receiver<Terminate>,
+) -> FinishedCommand {
+    let job = jobs.register(command.clone());
+
+    let mut command = command.to_vec();
+    command.push(b'\n');
+
+    let started_at = SystemTime::now();
+
+    let owner = owner.map(|owner| (owner, ProcessWatch::new(owner)));
+    let orphaned = || owner.as_ref().is_some_and(|(_, watch)| watch.has_exited());
+
+    // Keyed jobs wait a bit first, a newer job with the same key may make them useless.
+    let debounce = Duration::from_millis(config.supersession.debounce);
+    let cancellation = if orphaned() {
+        Some("The process which asked for the command has exited")
+    } else if key.is_some() && !debounce.is_zero() {
+        match termination_receiver.recv_timeout(debounce) {
+            Ok(Terminate::Superseded) => Some("Superseded before it started"),
+            Ok(Terminate::Cancelled) => Some("Cancelled before it started"),
+            Err(_) => None,
+        }
+    } else {
+        None
+    };
+
+    // Nobody is going to wait for the command anymore, so it is not even started.
+    if let Some(reason) = cancellation {
+        job.finish();
+        jobs.remove(job.id);
+        return FinishedCommand::Execution(FinishedExecution {
+            job_id: job.id,
             command,
-            result: ExecutionResult::FailedToSpawn(e)
-        }),
+            directory,
+            result: ExecutionResult::Cancelled(reason),
+            pid: None,
+            landlock: None,
+            started_at,
+            finished_at: SystemTime::now(),
+        });
+    }
+
+    // Kept until the job is over, the scratch directory is removed along with them.
+    let mut restrictions = None;
+    let spawned = config.landlock.enabled
+        .then(|| Restrictions::new(&config.landlock, directory.as_deref()))
+        .transpose()
+        .and_then(|created| {
+            restrictions = created.map(Arc::new);
+            spawn(&command, directory.as_deref(), restrictions.clone(), config)
+        });
+    let landlock = restrictions.as_ref().map(|restrictions| restrictions.to_json());
+
+    let Spawned { pid, stdout, stderr } = match spawned {
+        Ok(spawned) => spawned,
+        Err(e) => {
+            job.finish();
+            return FinishedCommand::Execution(FinishedExecution {
+                job_id: job.id,
+                command,
+                directory,
+                result: ExecutionResult::FailedToSpawn(e),
+                pid: None,
+                landlock,
+                started_at,
+                finished_at: SystemTime::now(),
+            });
+        }
     };
 
-    let result = loop {
-        match child.wait_timeout(std::time::Duration::from_secs(1)) {
-            Ok(Some(error_code)) => {
-                let mut stdout = child.stdout.take().unwrap();
-                let mut stderr = child.stderr.take().unwrap();
+    let (stdout_limit, stderr_limit) = (config.output.max_stdout_size, config.output.max_stderr_size);
+    let overflowed = &AtomicBool::new(false);
+    let exited = &AtomicBool::new(false);
 
-                let mut stdout_buffer = Vec::new();
-                let mut stderr_buffer = Vec::new();
+    // Both streams are drained while the command runs,
+    // otherwise it would block as soon as a pipe buffer fills up.
+    let result = thread::scope(|scope| {
+        let job = &job;
+        let stdout = scope.spawn(move || capture(stdout, job, Stream::Stdout, stdout_limit, overflowed, exited));
+        let stderr = scope.spawn(move || capture(stderr, job, Stream::Stderr, stderr_limit, overflowed, exited));
 
-                stdout.read_to_end(&mut stdout_buffer).unwrap();
-                stderr.read_to_end(&mut stderr_buffer).unwrap();
+        loop {
+            match has_exited(pid) {
+                Ok(true) => {
+                    // The job is over, whatever it left running in the background.
+                    exited.store(true, Ordering::Release);
 
-                break ExecutionResult::Ran {
-                    error_code: error_code.code().expect("Failed to get error code"),
-                    stdout: stdout_buffer,
-                    stderr: stderr_buffer,
-                };
-            }
-            Ok(None) => {
-                if let Ok(_) = termination_receiver.try_recv() {
-                    child.kill().unwrap();
-                    child.wait().unwrap();
+                    break match wait4(pid) {
+                        Ok((status, usage)) => ExecutionResult::Ran {
+                            error_code: exit_code(status),
+                            stdout: stdout.join().expect("Failed to join stdout reader"),
+                            stderr: stderr.join().expect("Failed to join stderr reader"),
+                            // Both readers are done, so nothing is appended anymore.
+                            combined: job.close_combined(),
+                            usage: usage.into(),
+                        },
+                        Err(e) => ExecutionResult::FailedToWait(e),
+                    };
+                }
+                Ok(false) => {
+                    let overflow_kill = config.output.on_overflow == OverflowAction::Kill
+                        && overflowed.load(Ordering::Relaxed);
+
+                    // The process is reaped by the next iteration.
+                    if overflow_kill || termination_receiver.try_recv().is_ok() {
+                        kill_group(pid);
+                    } else if orphaned() {
+                        eprintln!("Owner {} of job {} has exited, cancelling it", owner.as_ref().unwrap().0, job.id);
+                        kill_group(pid);
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                }
+                Err(e) => {
+                    kill_group(pid);
+                    exited.store(true, Ordering::Release);
+                    break ExecutionResult::FailedToWait(e);
                 }
             }
-            Err(e) => break ExecutionResult::FailedToWait(e),
         }
-    };
+    });
+
+    job.finish();
+
+    // Its output is of no use to anyone.
+    if orphaned() {
+        jobs.remove(job.id);
+    }
 
     FinishedCommand::Execution(FinishedExecution {
+        job_id: job.id,
         command,
+        directory,
         result,
+        pid: Some(pid),
+        landlock,
+        started_at,
+        finished_at: SystemTime::now(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs a job to completion with the given configuration.
+    fn run(config: &Config, job: impl Into<Job>) -> FinishedExecution {
+        run_in(&JobTable::default(), config, job)
+    }
+
+    /// Same as `run`, with the job in the given table.
+    fn run_in(jobs: &JobTable, config: &Config, job: impl Into<Job>) -> FinishedExecution {
+        let (_termination_sender, termination_receiver) = mpsc::channel();
+        let FinishedCommand::Execution(execution) = run_one(job.into(), config, jobs, termination_receiver) else {
+            unreachable!();
+        };
+        execution
+    }
+
+    #[test]
+    fn test_bundles() {
+        let execution = run(&Config::default(), b"printf out; printf err >&2; exit 3".to_vec());
+
+        let mut bundle = Vec::new();
+        execution.write_bundle_json(&mut bundle).unwrap();
+        let bundle: serde_json::Value = serde_json::from_slice(&bundle).unwrap();
+        assert_eq!(bundle["result"]["error_code"], 3);
+        assert_eq!(bundle["stdout"], "b3V0");
+        assert_eq!(bundle["stderr"], "ZXJy");
+        assert!(bundle["timings"]["wall_time"].as_f64().unwrap() >= 0.0);
+        assert_eq!(bundle["result"]["stdout_size"], 3);
+        assert_eq!(
+            bundle["result"]["stdout_sha256"],
+            "762069bc07a6e1b5df123a5ae7bd91c10daa04694fbaa17fba0cd6a8dcce8f22"
+        );
+
+        let mut bundle = Vec::new();
+        execution.write_bundle_cbor(&mut bundle).unwrap();
+        let bundle: ciborium::value::Value = ciborium::de::from_reader(&bundle[..]).unwrap();
+        let entries = bundle.as_map().unwrap();
+        let get = |key: &str| entries.iter().find(|(k, _)| k.as_text() == Some(key)).map(|(_, v)| v.clone());
+        assert_eq!(get("stdout"), Some(b"out".to_vec().into()));
+        assert_eq!(get("stderr"), Some(b"err".to_vec().into()));
+        assert!(get("timings").is_some());
+    }
+
+    #[test]
+    fn test_large_bundles() {
+        let execution = run(&Config::default(), b"head -c 70000 /dev/zero".to_vec());
+
+        let mut bundle = Vec::new();
+        execution.write_bundle_json(&mut bundle).unwrap();
+        let bundle: serde_json::Value = serde_json::from_slice(&bundle).unwrap();
+        assert_eq!(bundle["stdout"].as_str().unwrap().len(), 70000usize.div_ceil(3) * 4);
+
+        let mut bundle = Vec::new();
+        execution.write_bundle_cbor(&mut bundle).unwrap();
+        let bundle: ciborium::value::Value = ciborium::de::from_reader(&bundle[..]).unwrap();
+        let stdout = bundle.as_map().unwrap().iter().find(|(k, _)| k.as_text() == Some("stdout")).unwrap().1.clone();
+        assert_eq!(stdout, vec![0; 70000].into());
+    }
+
+    #[test]
+    fn test_combined_output() {
+        // Each line is written only once the previous one was read, so they can't be reordered.
+        // The command waits on a fifo of its own for each line, since the writer of one fifo
+        // may still have it open when it is opened again.
+        let directory = std::env::temp_dir().join(format!("typst-shell-escape-combined-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let fifos = [directory.join("one"), directory.join("two")];
+        for fifo in &fifos {
+            let path = std::ffi::CString::new(fifo.as_os_str().as_bytes()).unwrap();
+            // SAFETY: the path is a valid C string.
+            assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+        }
+
+        let command = format!(
+            "echo one; read x < {}; echo two >&2; read x < {}; echo three",
+            fifos[0].display(),
+            fifos[1].display(),
+        );
+        let jobs = Arc::new(JobTable::default());
+        let shell_jobs = jobs.clone();
+        let shell = thread::spawn(move || run_in(&shell_jobs, &Config::default(), command.into_bytes()));
+
+        for (fifo, stream, line) in [(&fifos[0], Stream::Stdout, "one\n"), (&fifos[1], Stream::Stderr, "two\n")] {
+            while !jobs.ids().iter().filter_map(|&id| jobs.get(id)).any(|job| {
+                job.output(stream).lock().unwrap().freeze().to_vec().unwrap() == line.as_bytes()
+            }) {
+                thread::sleep(Duration::from_millis(10));
+            }
+            std::fs::write(fifo, b"\n").unwrap();
+        }
+
+        let execution = shell.join().unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        let combined = execution.combined_output().unwrap();
+        assert_eq!(combined.text.to_vec().unwrap(), b"one\ntwo\nthree\n");
+
+        let json: serde_json::Value = serde_json::from_slice(&combined.json.to_vec().unwrap()).unwrap();
+        assert_eq!(json[1]["stream"], "stderr");
+        assert!(json[2]["time"].as_f64().unwrap() >= json[1]["time"].as_f64().unwrap());
+    }
+
+    #[test]
+    fn test_terminated_exit_code() {
+        let (termination_sender, termination_receiver) = mpsc::channel();
+        termination_sender.send(Terminate::Cancelled).unwrap();
+
+        let FinishedCommand::Execution(execution) = run_one(b"sleep 10".to_vec().into(), &Config::default(), &JobTable::default(), termination_receiver) else {
+            unreachable!();
+        };
+
+        let summary = execution.summarize_into_json();
+        assert_eq!(summary["result"]["error_code"], 128 + libc::SIGKILL);
+        assert!(summary["pid"].is_u64());
+    }
+
+    #[test]
+    fn test_orphaned_jobs() {
+        let jobs = JobTable::default();
+        let mut owner = std::process::Command::new("sleep").arg("0.2").spawn().unwrap();
+        let job = Job { command: b"sleep 10".to_vec(), directory: None, owner: Some(owner.id()), key: None };
+
+        let execution = run_in(&jobs, &Config::default(), job.clone());
+        owner.wait().unwrap();
+
+        let summary = execution.summarize_into_json();
+        assert_eq!(summary["result"]["error_code"], 128 + libc::SIGKILL);
+        assert!(jobs.ids().is_empty());
+
+        // Commands of processes which are already gone don't start at all.
+        let execution = run_in(&jobs, &Config::default(), job);
+        assert_eq!(execution.summarize_into_json()["result"]["error"], "Cancelled");
+        assert!(jobs.ids().is_empty());
+    }
+
+    #[test]
+    fn test_prefetch_reuse() {
+        let jobs = Arc::new(JobTable::default());
+        let (result_sender, result_receiver) = mpsc::channel();
+        let (command_sender, command_receiver) = mpsc::channel();
+        let shell_jobs = jobs.clone();
+        thread::spawn(move || super::run(Arc::default(), shell_jobs, result_sender, command_receiver));
+
+        let job = |owner: u32| Job { command: b"sleep 0.5; echo done".to_vec(), directory: None, owner: Some(owner), key: None };
+        let mut prefetcher = std::process::Command::new("sleep").arg("10").spawn().unwrap();
+        command_sender.send(Command::Prefetch(vec![job(prefetcher.id())])).unwrap();
+        while jobs.ids().is_empty() {
+            thread::sleep(POLL_INTERVAL);
+        }
+        let prefetched = jobs.ids()[0];
+
+        // The prefetched job outlives the process which asked for it,
+        // and is claimed by another one.
+        prefetcher.kill().unwrap();
+        prefetcher.wait().unwrap();
+        command_sender.send(Command::Execute(job(std::process::id()))).unwrap();
+        let FinishedCommand::Execution(execution) = result_receiver.recv().unwrap() else {
+            unreachable!();
+        };
+        assert_eq!(execution.job_id, prefetched);
+        assert_eq!(execution.summarize_into_json()["result"]["error_code"], 0);
+        assert_eq!(execution.outputs().0.to_vec().unwrap(), b"done\n");
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let jobs = Arc::new(JobTable::default());
+        let (result_sender, _result_receiver) = mpsc::channel();
+        let (command_sender, command_receiver) = mpsc::channel();
+        let shell_jobs = jobs.clone();
+        let shell = thread::spawn(move || super::run(Arc::default(), shell_jobs, result_sender, command_receiver));
+
+        command_sender.send(Command::Prefetch(vec![b"sleep 10".to_vec().into()])).unwrap();
+        command_sender.send(Command::Execute(b"sleep 20".to_vec().into())).unwrap();
+        while jobs.ids().len() < 2 {
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        let started = std::time::Instant::now();
+        command_sender.send(Command::Shutdown).unwrap();
+        shell.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(command_sender.send(Command::TerminateAll).is_err());
+    }
+
+    #[test]
+    fn test_supersession() {
+        let mut config = Config::default();
+        config.supersession.debounce = 100;
+
+        let (result_sender, result_receiver) = mpsc::channel();
+        let (command_sender, command_receiver) = mpsc::channel();
+        thread::spawn(move || super::run(Arc::new(config), Arc::default(), result_sender, command_receiver));
+
+        let keyed = |command: &[u8], key: &[u8]| Command::Execute(Job { key: Some(key.to_vec()), ..command.to_vec().into() });
+        let result = || match result_receiver.recv().unwrap() {
+            FinishedCommand::Execution(execution) => execution.summarize_into_json(),
+            FinishedCommand::Termination => unreachable!(),
+        };
+
+        // Superseded while debouncing, never started.
+        command_sender.send(keyed(b"echo first", b"k")).unwrap();
+        command_sender.send(keyed(b"echo second", b"k")).unwrap();
+        let first = result();
+        assert_eq!(first["command"], "echo first\n");
+        assert_eq!(first["result"]["error"], "Cancelled");
+        assert_eq!(first["result"]["message"], "Superseded before it started");
+        assert_eq!(result()["result"]["error_code"], 0);
+
+        // Terminated with everything else while debouncing.
+        command_sender.send(keyed(b"echo third", b"k")).unwrap();
+        command_sender.send(Command::TerminateAll).unwrap();
+        let third = result();
+        assert_eq!(third["result"]["error"], "Cancelled");
+        assert_eq!(third["result"]["message"], "Cancelled before it started");
+        assert!(matches!(result_receiver.recv().unwrap(), FinishedCommand::Termination));
+
+        // Superseded while running, killed.
+        command_sender.send(keyed(b"sleep 10", b"k")).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        command_sender.send(keyed(b"true", b"k")).unwrap();
+        command_sender.send(keyed(b"true", b"other")).unwrap();
+        let killed = result();
+        assert_eq!(killed["command"], "sleep 10\n");
+        assert_eq!(killed["result"]["error_code"], 128 + libc::SIGKILL);
+        assert_eq!(result()["result"]["error_code"], 0);
+        assert_eq!(result()["result"]["error_code"], 0);
+    }
+
+    #[test]
+    #[ignore = "needs unprivileged user namespaces"]
+    fn test_sandbox() {
+        let project = std::env::temp_dir().join(format!("typst-shell-escape-sandbox-{}", std::process::id()));
+        std::fs::create_dir_all(&project).unwrap();
+        std::fs::write(project.join("input"), "data\n").unwrap();
+
+        let hidden = std::env::current_dir().unwrap();
+        let mut config = Config::default();
+        config.sandbox.enabled = true;
+        config.sandbox.hide = vec![hidden.clone()];
+
+        let command = "echo $$; cat input; ls -A /proc | grep -c '^[0-9]'; wc -l < /proc/net/dev; touch x; ls -A /tmp; ls ";
+        let job = Job {
+            command: [command.as_bytes(), hidden.join("Cargo.toml").as_os_str().as_bytes()].concat(),
+            directory: Some(project.clone()),
+            owner: None,
+            key: None,
+        };
+        let execution = run(&config, job);
+        std::fs::remove_dir_all(&project).unwrap();
+
+        let (stdout, stderr) = execution.outputs();
+        let stdout = String::from_utf8(stdout.to_vec().unwrap()).unwrap();
+        // Pid 1 and nothing else but the pipeline, only loopback, a read-only project
+        // inside an empty /tmp, and the hidden directory is empty.
+        let lines = stdout.lines().collect::<Vec<_>>();
+        assert_eq!(lines[..2], ["1", "data"]);
+        assert!(lines[2].parse::<u32>().unwrap() <= 4, "{}", stdout);
+        assert_eq!(lines[3], "3");
+        assert_eq!(lines[4], project.strip_prefix("/tmp").unwrap().iter().next().unwrap().to_str().unwrap());
+        assert_eq!(lines.len(), 5, "{}", stdout);
+        let stderr = String::from_utf8(stderr.to_vec().unwrap()).unwrap();
+        assert!(stderr.contains("Read-only file system"), "{}", stderr);
+        assert!(stderr.contains("Cargo.toml"), "{}", stderr);
+    }
+
+    #[test]
+    #[ignore = "needs unprivileged user namespaces"]
+    fn test_sandbox_output() {
+        let mut config = Config::default();
+        config.sandbox.enabled = true;
+
+        // More than a pipe buffer, which only fits if the output is read while the shell runs.
+        let execution = run(&config, b"head -c 100000 /dev/zero".to_vec());
+        assert_eq!(execution.summarize_into_json()["result"]["error_code"], 0);
+        assert_eq!(execution.outputs().0.len(), 100000);
+    }
+
+    #[test]
+    fn test_landlock() {
+        let project = std::env::temp_dir().join(format!("typst-shell-escape-landlock-{}", std::process::id()));
+        std::fs::create_dir_all(&project).unwrap();
+        std::fs::write(project.join("input"), "data\n").unwrap();
+
+        let mut config = Config::default();
+        config.landlock.enabled = true;
+
+        let outside = std::env::current_dir().unwrap().join("Cargo.toml");
+        let command = "cat input; echo scratch > \"$TMPDIR/file\" && cat \"$TMPDIR/file\"; touch x; cat ";
+        let job = Job {
+            command: [command.as_bytes(), outside.as_os_str().as_bytes()].concat(),
+            directory: Some(project.clone()),
+            owner: None,
+            key: None,
+        };
+        let execution = run(&config, job);
+        std::fs::remove_dir_all(&project).unwrap();
+
+        // Not every kernel has it, but then it fails clearly instead of running unrestricted.
+        if crate::landlock::abi_version() == 0 {
+            let ExecutionResult::FailedToSpawn(e) = &execution.result else {
+                panic!("Ran without Landlock");
+            };
+            assert!(e.to_string().contains("kernel doesn't support it"), "{}", e);
+            return;
+        }
+
+        let (stdout, stderr) = execution.outputs();
+        assert_eq!(stdout.to_vec().unwrap(), b"data\nscratch\n");
+        let stderr = String::from_utf8(stderr.to_vec().unwrap()).unwrap();
+        assert_eq!(stderr.matches("Permission denied").count(), 2, "{}", stderr);
+
+        let landlock = &execution.summarize_into_json()["landlock"];
+        assert!(landlock["read"].as_array().unwrap().contains(&json!(project)));
+        let scratch = landlock["write"].as_array().unwrap().last().unwrap().as_str().unwrap();
+        assert!(!Path::new(scratch).exists());
+    }
+
+    #[test]
+    #[ignore = "needs root"]
+    fn test_user() {
+        let mut config = Config::default();
+        config.user.uid = Some(65534);
+        config.user.gid = Some(65534);
+        config.landlock.enabled = crate::landlock::abi_version() > 0;
+
+        let command = b"id -u; id -G; echo scratch > \"${TMPDIR:-/tmp}/file-$$\" && cat \"${TMPDIR:-/tmp}/file-$$\" && rm \"${TMPDIR:-/tmp}/file-$$\"";
+        let execution = run(&config, command.to_vec());
+
+        let (stdout, stderr) = execution.outputs();
+        assert_eq!(stdout.to_vec().unwrap(), b"65534\n65534\nscratch\n", "{}", String::from_utf8_lossy(&stderr.to_vec().unwrap()));
+    }
+
+    #[test]
+    fn test_directory() {
+        let directory = std::env::temp_dir().canonicalize().unwrap();
+        let job = Job { command: b"pwd".to_vec(), directory: Some(directory.clone()), owner: None, key: None };
+        let execution = run(&Config::default(), job);
+
+        let summary = execution.summarize_into_json();
+        assert_eq!(summary["directory"], directory.to_str().unwrap());
+        assert_eq!(execution.outputs().0.to_vec().unwrap(), format!("{}\n", directory.display()).into_bytes());
+        assert!(run(&Config::default(), b"true".to_vec()).summarize_into_json()["directory"].is_null());
+    }
+
+    #[test]
+    fn test_leftovers() {
+        // Jobs read the stdin of the daemon, which is usually its terminal.
+        let stdin = run(&Config::default(), b"readlink /proc/self/fd/0".to_vec()).outputs().0.to_vec().unwrap();
+        let expected = std::fs::read_link("/proc/self/fd/0").map_or_else(|_| Vec::new(), |path| path.as_os_str().as_bytes().to_vec());
+        assert_eq!(stdin.trim_ascii_end(), expected);
+
+        // Background processes outlive the shell and are not waited for, even if they still
+        // hold the output, and neither are processes which detached themselves.
+        let start = std::time::Instant::now();
+        let execution = run(&Config::default(), b"sleep 10 & setsid sleep 10 & echo $!".to_vec());
+        assert!(start.elapsed() < Duration::from_secs(5), "Waited for leftovers");
+        assert_eq!(execution.summarize_into_json()["result"]["error_code"], 0);
+
+        let output = execution.outputs().0.to_vec().unwrap();
+        let leftover = String::from_utf8(output).unwrap().trim().parse::<libc::pid_t>().unwrap();
+        // SAFETY: kill has no memory safety requirements.
+        assert_eq!(unsafe { libc::kill(leftover, 0) }, 0, "Background process was killed");
+        // SAFETY: as above.
+        unsafe { libc::kill(leftover, libc::SIGKILL) };
+    }
+
+    #[test]
+    fn test_output_limit() {
+        let mut config = Config::default();
+        config.output.max_stdout_size = Some(1000);
+        config.output.max_stderr_size = Some(10);
+
+        // Much more than a pipe buffer, to make sure the command is not blocked on writing.
+        let execution = run(&config, b"head -c 1000000 /dev/zero; echo 0123456789 >&2".to_vec());
+
+        let summary = execution.summarize_into_json();
+        assert_eq!(summary["result"]["error_code"], 0);
+        assert_eq!(summary["result"]["stdout_size"], 1000000);
+        assert_eq!(summary["result"]["stdout_truncated"], true);
+        assert_eq!(summary["result"]["stderr_size"], 11);
+        assert_eq!(summary["result"]["stderr_truncated"], true);
+        assert_eq!(execution.outputs().0.len(), 1000);
+        assert_eq!(execution.outputs().1.to_vec().unwrap(), b"0123456789");
+
+        config.output.on_overflow = OverflowAction::Kill;
+        let execution = run(&config, b"yes".to_vec());
+
+        let summary = execution.summarize_into_json();
+        assert_eq!(summary["result"]["error_code"], 128 + libc::SIGKILL);
+        assert_eq!(summary["result"]["stdout_truncated"], true);
+    }
+}
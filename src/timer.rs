@@ -0,0 +1,79 @@
+use std::collections::BTreeMap;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+/// Runs callbacks once their time comes, all of them on the same thread.
+///
+/// Requests are served one after the other, so a request which has to wait, like
+/// a read of a sleep file, is answered from here instead, whatever the number of
+/// requests waiting at the same time.
+#[derive(Debug, Default)]
+pub struct Timer {
+    callbacks: OnceLock<mpsc::Sender<(Instant, Callback)>>,
+}
+
+impl Timer {
+    /// Runs the callback after the given delay. Callbacks still waiting when the timer
+    /// is dropped are dropped along with it.
+    pub fn after(&self, delay: Duration, callback: impl FnOnce() + Send + 'static) {
+        let callbacks = self.callbacks.get_or_init(|| {
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || run(receiver));
+            sender
+        });
+        let _ = callbacks.send((Instant::now() + delay, Box::new(callback)));
+    }
+}
+
+fn run(receiver: mpsc::Receiver<(Instant, Callback)>) {
+    // Keyed by a sequence number too, so that callbacks due at the same instant all stay.
+    let mut pending = BTreeMap::<(Instant, u64), Callback>::new();
+    let mut sequence = 0;
+
+    loop {
+        let received = match pending.keys().next() {
+            Some(&(due, _)) => receiver.recv_timeout(due.saturating_duration_since(Instant::now())),
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match received {
+            Ok((due, callback)) => {
+                pending.insert((due, sequence), callback);
+                sequence += 1;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let now = Instant::now();
+        while let Some(entry) = pending.first_entry().filter(|entry| entry.key().0 <= now) {
+            entry.remove()();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timer() {
+        let timer = Timer::default();
+        let (sender, receiver) = mpsc::channel();
+
+        let start = Instant::now();
+        for (delay, value) in [(200, 3), (100, 2), (100, 1), (0, 0)] {
+            let sender = sender.clone();
+            timer.after(Duration::from_millis(delay), move || sender.send(value).unwrap());
+        }
+
+        let received = receiver.iter().take(4).collect::<Vec<_>>();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        // Callbacks due at the same time run in the order they came in.
+        assert_eq!(received, [0, 2, 1, 3]);
+    }
+}
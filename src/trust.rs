@@ -0,0 +1,246 @@
+//! Approval of commands by the user, asked in the terminal the first time a command is seen.
+//!
+//! Commands are identified by the SHA-256 of their normalized form, so that the same command
+//! written with different spacing is still the same. Line breaks separate commands, so they
+//! are kept as they are. Decisions to always allow or to deny
+//! are kept in a JSON file, which is read again for every command, so entries removed from it
+//! are revoked right away.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::decode::hex_encode;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Allow,
+    Deny,
+}
+
+/// What the user answered to a prompt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Answer {
+    Once,
+    Always,
+    Deny,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    /// Only for the one reviewing the file, the key is what is looked up.
+    command: String,
+    decision: Decision,
+    /// Unix timestamp.
+    decided_at: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct TrustStore {
+    pub path: PathBuf,
+}
+
+impl TrustStore {
+    /// Reads the decisions made so far. A missing file has none, but a malformed one is an error,
+    /// since it may well have been meant to deny something.
+    fn load(&self) -> io::Result<BTreeMap<String, Entry>> {
+        match File::open(&self.path) {
+            Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn lookup(&self, command: &[u8]) -> io::Result<Option<Decision>> {
+        Ok(self.load()?.get(&fingerprint(command)).map(|entry| entry.decision))
+    }
+
+    /// Adds a decision to the store. Nothing is written if the store can't be read,
+    /// so that a store which is broken for the moment is not replaced by an empty one.
+    pub fn remember(&self, command: &[u8], decision: Decision) -> io::Result<()> {
+        let mut entries = self.load()?;
+        let decided_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+
+        entries.insert(fingerprint(command), Entry {
+            command: String::from_utf8_lossy(&normalize(command)).to_string(),
+            decision,
+            decided_at,
+        });
+
+        // Written next to the store and moved over it, so that it is never seen half-written.
+        if let Some(directory) = self.path.parent() {
+            std::fs::create_dir_all(directory)?;
+        }
+        let temporary = self.path.with_extension("tmp");
+        let file = File::create(&temporary)?;
+        serde_json::to_writer_pretty(file, &entries)?;
+        std::fs::rename(&temporary, &self.path)
+    }
+
+    /// Whether the command may run, asking in the terminal if it wasn't decided yet.
+    /// Nothing is approved while the store can't be read.
+    pub fn approve(&self, command: &[u8]) -> Result<(), String> {
+        let decision = self.lookup(command)
+            .map_err(|e| format!("can't read {}: {}", self.path.display(), e))?;
+        match decision {
+            Some(Decision::Allow) => return Ok(()),
+            Some(Decision::Deny) => return Err(format!("denied in {}", self.path.display())),
+            None => {}
+        }
+
+        let answer = ask(command).map_err(|e| format!("can't ask for approval: {}", e))?;
+        let remembered = match answer {
+            Answer::Once => Ok(()),
+            Answer::Always => self.remember(command, Decision::Allow),
+            Answer::Deny => self.remember(command, Decision::Deny),
+        };
+        // The answer still holds for this time.
+        if let Err(e) = remembered {
+            eprintln!("Failed to write {}: {}", self.path.display(), e);
+        }
+
+        match answer {
+            Answer::Once | Answer::Always => Ok(()),
+            Answer::Deny => Err("denied in the terminal".to_string()),
+        }
+    }
+}
+
+/// Collapses runs of spaces and tabs outside of quotes and trims whitespace at the ends.
+fn normalize(command: &[u8]) -> Vec<u8> {
+    let mut normalized = Vec::with_capacity(command.len());
+    let mut quote = None;
+    let mut escaped = false;
+
+    for &c in command.trim_ascii() {
+        let space = matches!(c, b' ' | b'\t') && quote.is_none() && !escaped;
+        if !(space && normalized.last() == Some(&b' ')) {
+            normalized.push(if space { b' ' } else { c });
+        }
+
+        match (c, quote) {
+            _ if escaped => escaped = false,
+            (b'\\', Some(b'"') | None) => escaped = true,
+            (b'\'' | b'"', None) => quote = Some(c),
+            (c, Some(open)) if c == open => quote = None,
+            _ => {}
+        }
+    }
+
+    normalized
+}
+
+pub fn fingerprint(command: &[u8]) -> String {
+    let digest = Sha256::digest(normalize(command));
+    String::from_utf8(hex_encode(&digest)).expect("Hex is valid UTF-8")
+}
+
+fn parse_answer(line: &str) -> Option<Answer> {
+    match line.trim().to_ascii_lowercase().as_str() {
+        "o" | "once" => Some(Answer::Once),
+        "a" | "always" => Some(Answer::Always),
+        "d" | "deny" => Some(Answer::Deny),
+        _ => None,
+    }
+}
+
+/// The command as text which can't move the cursor or restyle the terminal, nor reorder
+/// what is shown, with control and bidirectional formatting characters escaped.
+fn escape_controls(command: &[u8]) -> String {
+    String::from_utf8_lossy(command)
+        .chars()
+        .map(|c| match c {
+            '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}' => c.escape_unicode().to_string(),
+            c if c.is_control() => c.escape_default().to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// Asks the user on the terminal of the daemon. The end of input counts as a denial.
+fn ask(command: &[u8]) -> std::io::Result<Answer> {
+    let tty = File::options().read(true).write(true).open("/dev/tty")?;
+    let mut output = &tty;
+    let mut input = BufReader::new(&tty);
+
+    writeln!(output, "\nThe document wants to run:\n    {}", escape_controls(command))?;
+    loop {
+        write!(output, "Allow it [o]nce, [a]lways, or [d]eny? ")?;
+        output.flush()?;
+
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(Answer::Deny);
+        }
+
+        if let Some(answer) = parse_answer(&line) {
+            return Ok(answer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(b"  ls   -la\t/ \n"), b"ls -la /");
+        assert_eq!(normalize(b"echo a \n rm x"), b"echo a \n rm x");
+        assert_ne!(fingerprint(b"echo a rm -rf ~"), fingerprint(b"echo a\nrm -rf ~"));
+        assert_eq!(normalize(b"echo 'a  b'  \"c  d\""), b"echo 'a  b' \"c  d\"");
+        assert_eq!(normalize(b"echo a\\  b"), b"echo a\\ b");
+        assert_eq!(fingerprint(b"ls  -la"), fingerprint(b" ls -la "));
+        assert_ne!(fingerprint(b"ls -la"), fingerprint(b"ls -l"));
+    }
+
+    #[test]
+    fn test_escape_controls() {
+        assert_eq!(escape_controls(b"ls -la"), "ls -la");
+        assert_eq!(escape_controls(b"rm -rf ~\rls\n"), "rm -rf ~\\rls\\n");
+        assert_eq!(escape_controls(b"\x1b[2Kls"), "\\u{1b}[2Kls");
+        assert_eq!(escape_controls("a\u{202e}b".as_bytes()), "a\\u{202e}b");
+    }
+
+    #[test]
+    fn test_parse_answer() {
+        assert_eq!(parse_answer("a\n"), Some(Answer::Always));
+        assert_eq!(parse_answer(" Once "), Some(Answer::Once));
+        assert_eq!(parse_answer("d"), Some(Answer::Deny));
+        assert_eq!(parse_answer("yes"), None);
+    }
+
+    #[test]
+    fn test_store() {
+        let path = std::env::temp_dir().join(format!("typst-shell-escape-trust-{}.json", std::process::id()));
+        let store = TrustStore { path: path.clone() };
+
+        assert_eq!(store.lookup(b"ls").unwrap(), None);
+        store.remember(b"ls", Decision::Allow).unwrap();
+        store.remember(b"rm -rf /", Decision::Deny).unwrap();
+        assert_eq!(store.lookup(b" ls ").unwrap(), Some(Decision::Allow));
+        assert_eq!(store.approve(b"ls"), Ok(()));
+        assert!(store.approve(b"rm  -rf /").is_err());
+
+        // Removing an entry from the file revokes it.
+        let mut entries = store.load().unwrap();
+        entries.remove(&fingerprint(b"ls"));
+        std::fs::write(&path, serde_json::to_vec(&entries).unwrap()).unwrap();
+        assert_eq!(store.lookup(b"ls").unwrap(), None);
+
+        // A broken store approves nothing and is left for the user to fix.
+        std::fs::write(&path, b"{").unwrap();
+        assert!(store.lookup(b"ls").is_err());
+        assert!(store.approve(b"ls").unwrap_err().contains("can't read"));
+        assert!(store.remember(b"ls", Decision::Allow).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"{");
+
+        std::fs::remove_file(path).unwrap();
+    }
+}